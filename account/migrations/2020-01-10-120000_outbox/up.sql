@@ -0,0 +1,12 @@
+create table outbox (
+  seq BIGSERIAL NOT NULL PRIMARY KEY,
+  topic TEXT NOT NULL,
+  record_key TEXT NOT NULL,
+  key_name TEXT,
+  record_name TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  sent_at TIMESTAMP
+  );
+
+  create index outbox_unsent on outbox (seq) where sent_at is null;
@@ -0,0 +1,7 @@
+create table inbox (
+  message_id TEXT NOT NULL PRIMARY KEY,
+  topic TEXT NOT NULL,
+  partition INTEGER NOT NULL,
+  message_offset BIGINT NOT NULL,
+  processed_at TIMESTAMP NOT NULL
+  );
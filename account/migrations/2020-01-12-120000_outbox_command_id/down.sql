@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table outbox drop column command_id;
\ No newline at end of file
@@ -0,0 +1,3 @@
+alter table outbox add column command_id TEXT;
+
+create index outbox_unsent_command on outbox (command_id) where sent_at is null;
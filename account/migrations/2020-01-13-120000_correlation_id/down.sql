@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table confirmed_transaction drop column correlation_id;
\ No newline at end of file
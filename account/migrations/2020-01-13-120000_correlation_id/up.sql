@@ -0,0 +1 @@
+alter table confirmed_transaction add column correlation_id TEXT;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+alter table balance
+  alter column amount type REAL,
+  alter column limits type REAL;
\ No newline at end of file
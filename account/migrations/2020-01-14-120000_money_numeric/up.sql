@@ -0,0 +1,3 @@
+alter table balance
+  alter column amount type NUMERIC(18, 2) using round(amount::numeric, 2),
+  alter column limits type NUMERIC(18, 2) using round(limits::numeric, 2);
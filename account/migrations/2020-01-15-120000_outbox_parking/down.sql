@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+drop index outbox_sent;
+drop index outbox_unsent;
+create index outbox_unsent on outbox (seq) where sent_at is null;
+
+alter table outbox drop column attempts, drop column last_error, drop column parked_at;
@@ -0,0 +1,8 @@
+alter table outbox
+  add column attempts INTEGER NOT NULL DEFAULT 0,
+  add column last_error TEXT,
+  add column parked_at TIMESTAMP;
+
+drop index outbox_unsent;
+create index outbox_unsent on outbox (seq) where sent_at is null and parked_at is null;
+create index outbox_sent on outbox (sent_at) where sent_at is not null;
@@ -0,0 +1,366 @@
+use crate::config::{Acks, BackendKind, Compression, FallbackOffset, KafkaConfig, Partitioner};
+use crate::memory_broker::MemoryBroker;
+use chrono::NaiveDateTime;
+use kafka::client::{CommitOffset, Compression as KafkaCompression, KafkaClient, RequiredAcks};
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use kafka::producer::{DefaultHasher, Producer, Record};
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Message as polled from a backend, owning its key and value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub offset: i64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>
+}
+
+/// Messages polled from one partition, in offset order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageSet {
+    pub topic: String,
+    pub partition: i32,
+    pub messages: Vec<Message>
+}
+
+/// Position to reset the offsets of a group to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OffsetReset {
+    Earliest,
+    Latest,
+    /// The same offset for all partitions, limited to the latest offset of each partition.
+    Offset(i64),
+    /// The first message at or after the time, in milliseconds since the epoch. Kafka looks this up per log segment, so
+    /// the offset might be somewhat earlier.
+    Time(i64)
+}
+
+impl FromStr for OffsetReset {
+    type Err = String;
+
+    /// Parses `earliest`, `latest`, an offset or a time like `2020-01-18T12:00:00` in UTC.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "earliest" => Ok(OffsetReset::Earliest),
+            "latest" => Ok(OffsetReset::Latest),
+            v => match (v.parse(), NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")) {
+                (Ok(offset), _) => Ok(OffsetReset::Offset(offset)),
+                (_, Ok(time)) => Ok(OffsetReset::Time(time.timestamp_millis())),
+                _ => Err(format!("Expected earliest, latest, an offset or a time like 2020-01-18T12:00:00, got {}", s))
+            }
+        }
+    }
+}
+
+/// Where a record ended up once the broker acknowledged it. The offset is -1 when no acknowledgement was required.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delivery {
+    pub partition: i32,
+    pub offset: i64
+}
+
+/// Consuming side of a broker, for a single group.
+pub trait ConsumerBackend {
+    /// Fetches the next messages of the subscribed topics, which might be none.
+    fn poll(&mut self) -> Result<Vec<MessageSet>, String>;
+    /// Marks a message as processed, so it's included in the next commit.
+    fn consume_message(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), String>;
+    /// Commits the offsets of the messages marked as processed.
+    fn commit_consumed(&mut self) -> Result<(), String>;
+}
+
+/// Producing side of a broker.
+pub trait ProducerBackend {
+    /// Number of partitions of the topic.
+    fn partitions(&mut self, topic: &str) -> Result<i32, String>;
+
+    /// How `send` picks the partition of a record.
+    fn partitioner(&self) -> Partitioner {
+        Partitioner::Client
+    }
+
+    /// Sends records to one partition in a single request, only returning once the broker acknowledged them. Returns
+    /// the offset of the first record, the others follow it in order, or -1 when no acknowledgement was required.
+    fn send_batch(&mut self, topic: &str, partition: i32, records: &[(&[u8], &[u8])]) -> Result<i64, String>;
+
+    /// Sends a record to the partition of its key, only returning once the broker acknowledged it.
+    fn send(&mut self, topic: &str, key: &[u8], value: &[u8]) -> Result<Delivery, String> {
+        let partition = partition_for(self.partitioner(), key, self.partitions(topic)?);
+        let offset = self.send_batch(topic, partition, &[(key, value)])?;
+        Ok(Delivery {
+            partition,
+            offset
+        })
+    }
+}
+
+/// Partition of a record from the hash of its key, so records with the same key keep their order. `Client` hashes like
+/// the kafka crate does when it picks the partition itself, `Murmur2` like the Java client, so a key ends up on the
+/// same partition whichever client sends it. Records without a key go to a random partition.
+pub fn partition_for(partitioner: Partitioner, key: &[u8], partitions: i32) -> i32 {
+    if key.is_empty() {
+        return rand::thread_rng().gen_range(0, partitions);
+    }
+    match partitioner {
+        Partitioner::Client => {
+            let mut hasher = DefaultHasher::default();
+            hasher.write(key);
+            (hasher.finish() as u32 % partitions as u32) as i32
+        }
+        Partitioner::Murmur2 => (murmur2(key) & 0x7fff_ffff) as i32 % partitions
+    }
+}
+
+fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+    let mut h = SEED ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// Broker the services connect to, as configured with `backend`. With `memory` everything in the process shares
+/// `MemoryBroker::global()`, so the services can run without Kafka.
+#[derive(Clone)]
+pub enum Backend {
+    Kafka(KafkaConfig),
+    Memory(MemoryBroker, Duration)
+}
+
+impl Backend {
+    pub fn new(config: &KafkaConfig) -> Backend {
+        match config.backend {
+            BackendKind::Kafka => Backend::Kafka(config.clone()),
+            BackendKind::Memory => Backend::Memory(MemoryBroker::global(), config.consumer.poll_timeout())
+        }
+    }
+
+    /// Consumer for `topics`, starting at the committed offsets of `group`, or the configured fallback offset when
+    /// there are none.
+    pub fn consumer(&self, group: &str, topics: &[String]) -> Result<Box<dyn ConsumerBackend>, String> {
+        match self {
+            Backend::Kafka(config) => {
+                let fallback_offset = match config.consumer.fallback_offset {
+                    FallbackOffset::Earliest => FetchOffset::Earliest,
+                    FallbackOffset::Latest => FetchOffset::Latest
+                };
+                let mut builder = Consumer::from_hosts(config.brokers.clone())
+                    .with_client_id(config.client_id.clone())
+                    .with_group(group.to_string())
+                    .with_fallback_offset(fallback_offset)
+                    .with_fetch_min_bytes(config.consumer.fetch_min_bytes)
+                    .with_fetch_max_bytes_per_partition(config.consumer.fetch_max_bytes_per_partition)
+                    .with_fetch_max_wait_time(config.consumer.poll_timeout())
+                    .with_offset_storage(GroupOffsetStorage::Kafka);
+                for topic in topics {
+                    builder = builder.with_topic(topic.clone());
+                }
+                let consumer = builder.create().map_err(|e| e.to_string())?;
+                Ok(Box::new(KafkaConsumer(consumer)))
+            }
+            Backend::Memory(broker, poll_wait) => Ok(Box::new(broker.consumer(group, topics).with_poll_wait(*poll_wait)))
+        }
+    }
+
+    /// Producer waiting for the configured acknowledgements of a record.
+    pub fn producer(&self) -> Result<Box<dyn ProducerBackend>, String> {
+        match self {
+            Backend::Kafka(config) => {
+                let required_acks = match config.producer.required_acks {
+                    Acks::None => RequiredAcks::None,
+                    Acks::One => RequiredAcks::One,
+                    Acks::All => RequiredAcks::All
+                };
+                let compression = match config.producer.compression {
+                    Compression::None => KafkaCompression::NONE,
+                    Compression::Gzip => KafkaCompression::GZIP,
+                    Compression::Snappy => KafkaCompression::SNAPPY
+                };
+                let producer = Producer::from_hosts(config.brokers.clone())
+                    .with_client_id(config.client_id.clone())
+                    .with_ack_timeout(config.producer.ack_timeout())
+                    .with_required_acks(required_acks)
+                    .with_compression(compression)
+                    .create()
+                    .map_err(|e| e.to_string())?;
+                let mut client = KafkaClient::new(config.brokers.clone());
+                client.set_client_id(config.client_id.clone());
+                Ok(Box::new(KafkaProducer {
+                    producer,
+                    client,
+                    partitions: HashMap::new(),
+                    partitioner: config.producer.partitioner
+                }))
+            }
+            Backend::Memory(broker, _) => Ok(Box::new(broker.producer()))
+        }
+    }
+
+    /// Commits the offsets `group` continues the partitions of `topic` from, returning them per partition. Consumers
+    /// of the group that are running keep committing their own offsets, so stop them first.
+    pub fn reset_offsets(&self, group: &str, topic: &str, to: OffsetReset) -> Result<Vec<(i32, i64)>, String> {
+        match self {
+            Backend::Kafka(config) => {
+                let mut client = KafkaClient::new(config.brokers.clone());
+                client.set_client_id(config.client_id.clone());
+                client.set_group_offset_storage(GroupOffsetStorage::Kafka);
+                client.load_metadata(&[topic]).map_err(|e| e.to_string())?;
+                let fetch_offset = match to {
+                    OffsetReset::Earliest => FetchOffset::Earliest,
+                    OffsetReset::Time(time) => FetchOffset::ByTime(time),
+                    OffsetReset::Latest | OffsetReset::Offset(_) => FetchOffset::Latest
+                };
+                let partition_offsets = client.fetch_topic_offsets(topic, fetch_offset).map_err(|e| e.to_string())?;
+                let offsets: Vec<(i32, i64)> = partition_offsets
+                    .iter()
+                    .map(|po| match to {
+                        OffsetReset::Offset(offset) => (po.partition, offset.max(0).min(po.offset)),
+                        _ => (po.partition, po.offset)
+                    })
+                    .collect();
+                let commits: Vec<CommitOffset> = offsets
+                    .iter()
+                    .map(|(partition, offset)| CommitOffset::new(topic, *partition, *offset))
+                    .collect();
+                client.commit_offsets(group, &commits).map_err(|e| e.to_string())?;
+                Ok(offsets)
+            }
+            Backend::Memory(broker, _) => broker.reset_offsets(group, topic, to)
+        }
+    }
+}
+
+struct KafkaConsumer(Consumer);
+
+impl ConsumerBackend for KafkaConsumer {
+    fn poll(&mut self) -> Result<Vec<MessageSet>, String> {
+        let mss = self.0.poll().map_err(|e| e.to_string())?;
+        Ok(mss
+            .iter()
+            .map(|ms| MessageSet {
+                topic: ms.topic().to_string(),
+                partition: ms.partition(),
+                messages: ms
+                    .messages()
+                    .iter()
+                    .map(|m| Message {
+                        offset: m.offset,
+                        key: m.key.to_vec(),
+                        value: m.value.to_vec()
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn consume_message(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), String> {
+        self.0.consume_message(topic, partition, offset).map_err(|e| e.to_string())
+    }
+
+    fn commit_consumed(&mut self) -> Result<(), String> {
+        self.0.commit_consumed().map_err(|e| e.to_string())
+    }
+}
+
+struct KafkaProducer {
+    producer: Producer,
+    /// Client for the metadata of the topics.
+    client: KafkaClient,
+    /// Number of partitions per topic, once looked up.
+    partitions: HashMap<String, i32>,
+    partitioner: Partitioner
+}
+
+impl ProducerBackend for KafkaProducer {
+    fn partitions(&mut self, topic: &str) -> Result<i32, String> {
+        if let Some(partitions) = self.partitions.get(topic) {
+            return Ok(*partitions);
+        }
+        self.client.load_metadata(&[topic]).map_err(|e| e.to_string())?;
+        let partitions = self.client.topics().partitions(topic).map_or(0, |partitions| partitions.len() as i32);
+        if partitions == 0 {
+            return Err(format!("No partitions known for {}", topic));
+        }
+        self.partitions.insert(topic.to_string(), partitions);
+        Ok(partitions)
+    }
+
+    fn partitioner(&self) -> Partitioner {
+        self.partitioner
+    }
+
+    fn send_batch(&mut self, topic: &str, partition: i32, records: &[(&[u8], &[u8])]) -> Result<i64, String> {
+        let records: Vec<Record<&[u8], &[u8]>> = records
+            .iter()
+            .map(|(key, value)| Record::from_key_value(topic, *key, *value).with_partition(partition))
+            .collect();
+        let confirms = self.producer.send_all(&records).map_err(|e| e.to_string())?;
+        match confirms.into_iter().flat_map(|c| c.partition_confirms).next() {
+            Some(confirm) => confirm.offset.map_err(|code| format!("Records not accepted: {:?}", code)),
+            None => Ok(-1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::partition_for;
+    use crate::config::Partitioner;
+
+    #[test]
+    fn murmur2_partitions_match_the_java_client() {
+        // Hashes of the Java client's tests, masked to positive like it does before taking the modulo.
+        let hashes = [
+            ("21", -973_932_308),
+            ("foobar", -790_332_482),
+            ("a-little-bit-long-string", -985_981_536),
+            ("a-little-bit-longer-string", -1_486_304_829),
+            ("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8", -58_897_971),
+            ("abc", 479_470_107)
+        ];
+        for (key, hash) in &hashes {
+            assert_eq!(
+                partition_for(Partitioner::Murmur2, key.as_bytes(), i32::max_value()),
+                hash & 0x7fff_ffff,
+                "{}",
+                key
+            );
+        }
+        assert_eq!(partition_for(Partitioner::Murmur2, b"abc", 6), 479_470_107 % 6);
+    }
+
+    #[test]
+    fn client_partitions_use_the_xxhash_of_the_key() {
+        assert_eq!(partition_for(Partitioner::Client, b"abc", i32::max_value()), 0x32d1_53ff);
+        assert_eq!(partition_for(Partitioner::Client, b"NL66OPEN0000000000", 6), 2);
+    }
+
+    #[test]
+    fn records_without_a_key_can_go_to_any_partition() {
+        for partitioner in &[Partitioner::Client, Partitioner::Murmur2] {
+            assert!((0..100).all(|_| (0..3).contains(&partition_for(*partitioner, b"", 3))));
+        }
+    }
+}
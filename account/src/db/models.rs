@@ -1,7 +1,7 @@
 use crate::db::schema::*;
 use crate::db::util::*;
 use crate::db::DbConn;
-use avro_rs::types::Value;
+use crate::money::Money;
 use chrono::{NaiveDateTime, Utc};
 use diesel::{self, prelude::*};
 use log::warn;
@@ -14,14 +14,14 @@ pub struct Balance {
     pub account_no: String,
     pub token: String,
     pub account_type: String,
-    pub amount: f64,
-    pub limits: f64,
+    pub amount: Money,
+    pub limits: Money,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime
 }
 
 impl Balance {
-    pub fn new(account_no: String, token: String, tp: String, conn: &DbConn) -> () {
+    pub fn new(account_no: String, token: String, tp: String, conn: &DbConn) -> QueryResult<usize> {
         let now = Utc::now().naive_utc();
 
         let new_balance = Self {
@@ -29,20 +29,17 @@ impl Balance {
             account_no: account_no,
             token: token,
             account_type: tp,
-            amount: 0.0,
-            limits: -50000.0,
+            amount: Money::ZERO,
+            limits: Money::from_cents(-5_000_000),
             updated_at: now,
             created_at: now
         };
 
-        diesel::insert_into(balance::table)
-            .values(new_balance)
-            .execute(&**conn)
-            .expect("Error saving new balance");
+        diesel::insert_into(balance::table).values(new_balance).execute(&**conn)
     }
 
-    pub fn get_balance_by_account_no(account_no: String, conn: &DbConn) -> Option<Balance> {
-        balance::table.filter(balance::account_no.eq(account_no)).first::<Balance>(&**conn).ok()
+    pub fn get_balance_by_account_no(account_no: String, conn: &DbConn) -> QueryResult<Option<Balance>> {
+        balance::table.filter(balance::account_no.eq(account_no)).first::<Balance>(&**conn).optional()
     }
 }
 
@@ -82,17 +79,13 @@ impl ConfirmedAccount {
             .unwrap()
     }
 
-    pub fn get_cac(id: String, tp: String, conn: &DbConn) -> ConfirmedAccount {
-        match confirmed_account::table.find(id.clone()).first::<ConfirmedAccount>(&**conn).optional() {
-            Ok(Some(v)) => v,
-            Ok(None) => ConfirmedAccount::create_cac(id, tp, conn),
-            Err(e) => panic!("Error trying to get confirmed ccount creation with id: {:?} and error: {}", id, e)
-        }
+    pub fn find(id: &str, conn: &DbConn) -> QueryResult<Option<ConfirmedAccount>> {
+        confirmed_account::table.find(id).first::<ConfirmedAccount>(&**conn).optional()
     }
 
-    pub fn create_cac(id: String, tp: String, conn: &DbConn) -> ConfirmedAccount {
+    pub fn create_cac(id: String, tp: String, conn: &DbConn) -> QueryResult<ConfirmedAccount> {
         let account_no = new_account();
-        let reason = match Balance::get_balance_by_account_no(account_no.clone(), &conn) {
+        let reason = match Balance::get_balance_by_account_no(account_no.clone(), &conn)? {
             Some(_v) => Option::from("generated account no already exists, try again"),
             None => None
         };
@@ -102,15 +95,12 @@ impl ConfirmedAccount {
         };
 
         if reason == None {
-            Balance::new(account_no.clone(), token.clone(), tp.clone(), conn);
+            Balance::new(account_no.clone(), token.clone(), tp.clone(), conn)?;
         };
 
         let new_cac = ConfirmedAccount::new(id, account_no.clone(), token.clone(), tp.clone(), Option::from(reason.map(|s| s.to_string())));
 
-        diesel::insert_into(confirmed_account::table)
-            .values(&new_cac)
-            .get_result(&**conn)
-            .expect("Error saving new account")
+        diesel::insert_into(confirmed_account::table).values(&new_cac).get_result(&**conn)
     }
 }
 
@@ -120,67 +110,57 @@ impl ConfirmedAccount {
 pub struct ConfirmedTransaction {
     pub id: String,
     pub reason: Option<String>,
-    pub created_at: NaiveDateTime
+    pub created_at: NaiveDateTime,
+    /// Correlation id of the request the transfer was made for.
+    pub correlation_id: Option<String>
 }
 
 impl ConfirmedTransaction {
-    pub fn new(id: String, reason: Option<String>) -> Self {
-        let now = Utc::now().naive_utc();
-
+    pub fn new(id: String, reason: Option<String>, created_at: NaiveDateTime, correlation_id: Option<String>) -> Self {
         Self {
             id: id,
             reason: reason, // reason.map(|s| s.to_string()),
-            created_at: now
+            created_at,
+            correlation_id
         }
     }
 
-    pub fn get_cmt(id: String, values: &[(String, Value)], conn: &DbConn) -> (ConfirmedTransaction, Option<Balance>, Option<Balance>) {
-        match confirmed_transaction::table.find(id.clone()).first::<ConfirmedTransaction>(&**conn).optional() {
-            Ok(Some(v)) => (v, None, None),
-            Ok(None) => (ConfirmedTransaction::create_cmt(id, values, conn)),
-            Err(e) => panic!("Error trying to get confirmed account with id: {:?} and error: {}", id, e)
-        }
+    pub fn find(id: &str, conn: &DbConn) -> QueryResult<Option<ConfirmedTransaction>> {
+        confirmed_transaction::table.find(id).first::<ConfirmedTransaction>(&**conn).optional()
     }
 
-    pub fn create_cmt(id: String, values: &[(String, Value)], conn: &DbConn) -> (ConfirmedTransaction, Option<Balance>, Option<Balance>) {
-        let from = match values[3] {
-            (ref _from, Value::String(ref v)) => v.clone(),
-            _ => panic!("Not a string value, while that was expected")
-        };
-        let to = match values[4] {
-            (ref _to, Value::String(ref v)) => v.clone(),
-            _ => panic!("Not a string value, while that was expected")
-        };
-
+    pub fn create_cmt(
+        id: String,
+        amount: Money,
+        from: String,
+        to: String,
+        created_at: NaiveDateTime,
+        correlation_id: Option<String>,
+        conn: &DbConn
+    ) -> QueryResult<(ConfirmedTransaction, Option<Balance>, Option<Balance>)> {
         let (reason, b_from, b_to) = if invalid_from(from.clone()) {
             (Option::from("from is invalid"), None, None)
         } else if from == to {
             (Option::from("from and to can't be same for transfer"), None, None)
         } else {
-            ConfirmedTransaction::transfer(values, from, to, conn)
+            ConfirmedTransaction::transfer(amount, from, to, conn)?
         };
 
-        let new_confirmed_account = ConfirmedTransaction::new(id, reason.map(|s| s.to_string()));
+        let new_confirmed_account = ConfirmedTransaction::new(id, reason.map(|s| s.to_string()), created_at, correlation_id);
         let cmt = diesel::insert_into(confirmed_transaction::table)
             .values(&new_confirmed_account)
-            .get_result(&**conn)
-            .expect("Error saving new balance");
-        (cmt, b_from, b_to)
+            .get_result(&**conn)?;
+        Ok((cmt, b_from, b_to))
     }
 
-    fn transfer(values: &[(String, Value)], from: String, to: String, conn: &DbConn) -> (Option<&'static str>, Option<Balance>, Option<Balance>) {
-        let am = match values[2] {
-            (ref _amount, Value::Double(ref v)) => v,
-            _ => panic!("Not a Long value, while that was expected")
-        };
+    fn transfer(am: Money, from: String, to: String, conn: &DbConn) -> QueryResult<(Option<&'static str>, Option<Balance>, Option<Balance>)> {
         let (reason, b_from) = if valid_open_account(from.clone()) {
-            match Balance::get_balance_by_account_no(from.clone(), &conn) {
+            match Balance::get_balance_by_account_no(from.clone(), &conn)? {
                 Some(v) => {
-                    let b_from = match diesel::update(&v).set(balance::amount.eq(balance::amount - am)).get_result::<Balance>(&**conn) {
-                        Ok(v) => Option::from(v),
-                        Err(e) => panic!("error updating balance with account no: {}, error: {}", to, e)
-                    };
-                    (None, b_from)
+                    let b_from = diesel::update(&v)
+                        .set(balance::amount.eq(balance::amount - am))
+                        .get_result::<Balance>(&**conn)?;
+                    (None, Option::from(b_from))
                 }
                 None => {
                     warn!("Valid open account no {} not found", from.clone());
@@ -194,11 +174,12 @@ impl ConfirmedTransaction {
         let b_to = match reason {
             None => {
                 if valid_open_account(to.clone()) {
-                    match Balance::get_balance_by_account_no(to.clone(), conn) {
-                        Some(v) => match diesel::update(&v).set(balance::amount.eq(balance::amount + am)).get_result::<Balance>(&**conn) {
-                            Ok(v) => Option::from(v),
-                            Err(e) => panic!("error updating balance with account no: {}, error: {}", to.clone(), e)
-                        },
+                    match Balance::get_balance_by_account_no(to.clone(), conn)? {
+                        Some(v) => Option::from(
+                            diesel::update(&v)
+                                .set(balance::amount.eq(balance::amount + am))
+                                .get_result::<Balance>(&**conn)?
+                        ),
                         None => {
                             warn!("Valid open account no {} not found", from);
                             None
@@ -210,6 +191,103 @@ impl ConfirmedTransaction {
             }
             Some(_) => None
         };
-        (reason, b_from, b_to)
+        Ok((reason, b_from, b_to))
+    }
+}
+
+/// Record waiting to be published by the outbox relay, stored in the same database transaction as the changes it's
+/// about. The key and value are stored as the json of the generated event structs.
+#[derive(Debug, PartialEq, Identifiable, Queryable)]
+#[primary_key(seq)]
+#[table_name = "outbox"]
+pub struct Outbox {
+    pub seq: i64,
+    pub topic: String,
+    pub record_key: String,
+    /// Full name of the key record when `record_key` is an Avro key, none for a plain string key.
+    pub key_name: Option<String>,
+    pub record_name: String,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    /// Id of the command the record was emitted for, shared by the records staged together.
+    pub command_id: Option<String>,
+    /// Times publishing the record failed because it couldn't be encoded.
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    /// When the relay gave up on the record. Parked records are skipped until `parked_at` is cleared again.
+    pub parked_at: Option<NaiveDateTime>
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "outbox"]
+pub struct NewOutbox {
+    pub topic: String,
+    pub record_key: String,
+    pub key_name: Option<String>,
+    pub record_name: String,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    pub command_id: Option<String>
+}
+
+impl Outbox {
+    pub fn insert(new_outbox: &NewOutbox, conn: &DbConn) -> QueryResult<usize> {
+        diesel::insert_into(outbox::table).values(new_outbox).execute(&**conn)
+    }
+
+    /// Inserts the records with a single statement, so either all or none of them are stored.
+    pub fn insert_all(new_outboxes: &[NewOutbox], conn: &DbConn) -> QueryResult<usize> {
+        diesel::insert_into(outbox::table).values(new_outboxes).execute(&**conn)
+    }
+
+    /// Oldest records not yet published and not parked, in the order they were stored.
+    pub fn unsent(limit: i64, conn: &DbConn) -> QueryResult<Vec<Outbox>> {
+        outbox::table
+            .filter(outbox::sent_at.is_null())
+            .filter(outbox::parked_at.is_null())
+            .order(outbox::seq.asc())
+            .limit(limit)
+            .load::<Outbox>(&**conn)
+    }
+
+    /// Records of the commands not yet published and not parked, in the order they were stored.
+    pub fn unsent_of_commands(command_ids: Vec<String>, conn: &DbConn) -> QueryResult<Vec<Outbox>> {
+        outbox::table
+            .filter(outbox::sent_at.is_null())
+            .filter(outbox::parked_at.is_null())
+            .filter(outbox::command_id.eq_any(command_ids))
+            .order(outbox::seq.asc())
+            .load::<Outbox>(&**conn)
+    }
+
+    /// Marks the records as sent with a single statement.
+    pub fn mark_all_sent(seqs: &[i64], conn: &DbConn) -> QueryResult<usize> {
+        diesel::update(outbox::table.filter(outbox::seq.eq_any(seqs)))
+            .set(outbox::sent_at.eq(Utc::now().naive_utc()))
+            .execute(&**conn)
+    }
+
+    /// Counts a failed attempt to publish the records, parking all of them once one has failed `max_attempts` times.
+    /// Returns whether they were parked.
+    pub fn record_failure(seqs: &[i64], error: &str, max_attempts: i32, conn: &DbConn) -> QueryResult<bool> {
+        conn.transaction(|| {
+            let attempts = diesel::update(outbox::table.filter(outbox::seq.eq_any(seqs)))
+                .set((outbox::attempts.eq(outbox::attempts + 1), outbox::last_error.eq(error)))
+                .returning(outbox::attempts)
+                .get_results::<i32>(&**conn)?;
+            if attempts.iter().all(|attempts| *attempts < max_attempts) {
+                return Ok(false);
+            }
+            diesel::update(outbox::table.filter(outbox::seq.eq_any(seqs)))
+                .set(outbox::parked_at.eq(Utc::now().naive_utc()))
+                .execute(&**conn)?;
+            Ok(true)
+        })
+    }
+
+    /// Deletes the records sent before `sent_before`, returning how many were deleted.
+    pub fn delete_sent_before(sent_before: NaiveDateTime, conn: &DbConn) -> QueryResult<usize> {
+        diesel::delete(outbox::table.filter(outbox::sent_at.lt(sent_before))).execute(&**conn)
     }
 }
@@ -4,8 +4,8 @@ table! {
         account_no -> Text,
         token -> Text,
         account_type -> Text,
-        amount -> Double,
-        limits -> Double,
+        amount -> Numeric,
+        limits -> Numeric,
         created_at -> Timestamp,
         updated_at -> Timestamp,
     }
@@ -28,7 +28,35 @@ table! {
         id -> Text,
         reason -> Nullable<Text>,
         created_at -> Timestamp,
+        correlation_id -> Nullable<Text>,
     }
 }
 
-allow_tables_to_appear_in_same_query!(balance, confirmed_account, confirmed_transaction);
+table! {
+    inbox (message_id) {
+        message_id -> Text,
+        topic -> Text,
+        partition -> Int4,
+        message_offset -> Int8,
+        processed_at -> Timestamp,
+    }
+}
+
+table! {
+    outbox (seq) {
+        seq -> Int8,
+        topic -> Text,
+        record_key -> Text,
+        key_name -> Nullable<Text>,
+        record_name -> Text,
+        payload -> Text,
+        created_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        command_id -> Nullable<Text>,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        parked_at -> Nullable<Timestamp>,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(balance, confirmed_account, confirmed_transaction, inbox, outbox);
@@ -30,6 +30,11 @@ fn get_check_nr(digits: &str) -> String {
     }
 }
 
+/// Bank code of an open account, like `OPEN` for `NL66OPEN0000000000`.
+pub fn bank_code(account_no: &str) -> String {
+    account_no.get(4..8).unwrap_or_default().to_string()
+}
+
 pub fn invalid_from(from: String) -> bool {
     if "cash" == from {
         false
@@ -0,0 +1,622 @@
+use crate::events::ALL_SCHEMAS;
+use crate::kafka_producer::SubjectNameKind;
+use crate::schema_registry::Compatibility;
+#[cfg(test)]
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde_json::{json, Value as Json};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+
+/// Stand-in for the Confluent schema registry, implementing the endpoints used by the services and the schema registry
+/// converters. Schema ids are assigned in order of registration, starting at 1. Compatibility is checked with the
+/// schema resolution rules of the Avro specification, at the level set for the subject or `BACKWARD` when none is set.
+#[derive(Default)]
+pub struct Registry {
+    /// Registered schemas, the id of a schema is its index plus one.
+    schemas: Vec<Json>,
+    /// Schema id of each version per subject, the version is the index plus one.
+    subjects: HashMap<String, Vec<usize>>,
+    compatibility: HashMap<String, Compatibility>
+}
+
+impl Registry {
+    /// Registry with the schemas of both services, under the subjects of their configured subject name strategy. As
+    /// they're registered in the order of `ALL_SCHEMAS`, the stand-ins of both services use the same ids.
+    pub fn preloaded() -> Registry {
+        let mut registry = Registry::default();
+        for schema_file in ALL_SCHEMAS {
+            let subject = match SubjectNameKind::for_topic(schema_file.topic) {
+                Ok(kind) => kind.subject(schema_file.topic, schema_file.name, schema_file.is_key),
+                Err(e) => panic!("Error preloading schema registry: {}", e)
+            };
+            let schema = match serde_json::from_str(schema_file.schema) {
+                Ok(v) => v,
+                Err(e) => panic!("Error parsing schema for {}: {}", subject, e)
+            };
+            registry.register(&subject, schema);
+        }
+        registry
+    }
+
+    /// Returns the id of the schema, which is the existing one when the schema is already known.
+    pub fn register(&mut self, subject: &str, schema: Json) -> usize {
+        let id = match self.schemas.iter().position(|s| *s == schema) {
+            Some(index) => index + 1,
+            None => {
+                self.schemas.push(schema);
+                self.schemas.len()
+            }
+        };
+        let versions = self.subjects.entry(subject.to_string()).or_insert_with(Vec::new);
+        if !versions.contains(&id) {
+            versions.push(id);
+        }
+        id
+    }
+
+    fn compatibility(&self, subject: &str) -> Compatibility {
+        self.compatibility.get(subject).cloned().unwrap_or(Compatibility::Backward)
+    }
+
+    /// Whether `schema` can be registered for `subject`, checking it against all versions for a transitive level and
+    /// against the latest one otherwise. A schema that already is a version of the subject can always be registered.
+    fn can_register(&self, subject: &str, schema: &Json) -> bool {
+        let versions = match self.subjects.get(subject) {
+            Some(v) => v,
+            None => return true
+        };
+        if versions.iter().any(|id| self.schemas[id - 1] == *schema) {
+            return true;
+        }
+        let against = match self.compatibility(subject) {
+            Compatibility::BackwardTransitive | Compatibility::ForwardTransitive | Compatibility::FullTransitive => &versions[..],
+            _ => &versions[versions.len() - 1..]
+        };
+        self.is_compatible(subject, schema, against)
+    }
+
+    /// Whether `schema` is compatible with the schemas of `ids` at the level of `subject`. Backward means the new
+    /// schema can read data written with the existing one, forward the other way around.
+    fn is_compatible(&self, subject: &str, schema: &Json, ids: &[usize]) -> bool {
+        let (backward, forward) = match self.compatibility(subject) {
+            Compatibility::None => return true,
+            Compatibility::Backward | Compatibility::BackwardTransitive => (true, false),
+            Compatibility::Forward | Compatibility::ForwardTransitive => (false, true),
+            Compatibility::Full | Compatibility::FullTransitive => (true, true)
+        };
+        ids.iter().all(|id| {
+            let existing = &self.schemas[id - 1];
+            (!backward || can_read(schema, existing)) && (!forward || can_read(existing, schema))
+        })
+    }
+
+    /// Version and schema id, for a version number or `latest`.
+    fn version(&self, subject: &str, version: &str) -> Option<(usize, usize)> {
+        let versions = self.subjects.get(subject)?;
+        let version = match version {
+            "latest" | "-1" => versions.len(),
+            v => v.parse().ok()?
+        };
+        match version {
+            0 => None,
+            v => versions.get(v - 1).map(|id| (v, *id))
+        }
+    }
+
+    fn handle(&mut self, method: &str, path: &str, body: &Json) -> (u16, Json) {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["schemas", "ids", id]) => match id.parse::<usize>().ok().and_then(|id| self.schemas.get(id.wrapping_sub(1))) {
+                Some(schema) => (200, json!({ "schema": schema.to_string() })),
+                None => error(404, 40403, "Schema not found")
+            },
+            ("GET", ["subjects"]) => (200, json!(self.subjects.keys().collect::<Vec<_>>())),
+            ("GET", ["subjects", subject, "versions"]) => match self.subjects.get(*subject) {
+                Some(versions) => (200, json!((1..=versions.len()).collect::<Vec<_>>())),
+                None => error(404, 40401, "Subject not found")
+            },
+            ("GET", ["subjects", subject, "versions", version]) => match self.version(subject, version) {
+                Some((version, id)) => (
+                    200,
+                    json!({ "subject": subject, "version": version, "id": id, "schema": self.schemas[id - 1].to_string() })
+                ),
+                None if self.subjects.contains_key(*subject) => error(404, 40402, "Version not found"),
+                None => error(404, 40401, "Subject not found")
+            },
+            ("POST", ["subjects", subject, "versions"]) => match schema_of(body) {
+                Some(schema) if self.can_register(subject, &schema) => (200, json!({ "id": self.register(subject, schema) })),
+                Some(_) => error(409, 409, "Schema being registered is incompatible with an earlier schema"),
+                None => error(422, 42201, "Invalid schema")
+            },
+            ("POST", ["compatibility", "subjects", subject, "versions", version]) => match (self.version(subject, version), schema_of(body)) {
+                (Some((_, id)), Some(schema)) => (200, json!({ "is_compatible": self.is_compatible(subject, &schema, &[id]) })),
+                (Some(_), None) => error(422, 42201, "Invalid schema"),
+                (None, _) if self.subjects.contains_key(*subject) => error(404, 40402, "Version not found"),
+                (None, _) => error(404, 40401, "Subject not found")
+            },
+            ("PUT", ["config", subject]) => match body["compatibility"].as_str().and_then(Compatibility::from_name) {
+                Some(compatibility) => {
+                    self.compatibility.insert(subject.to_string(), compatibility);
+                    (200, json!({ "compatibility": compatibility.as_str() }))
+                }
+                None => error(422, 42203, "Invalid compatibility level")
+            },
+            ("GET", ["config", subject]) => match self.compatibility.get(*subject) {
+                Some(compatibility) => (200, json!({ "compatibilityLevel": compatibility.as_str() })),
+                None => error(404, 40408, "Subject does not have subject-level compatibility configured")
+            },
+            ("DELETE", ["config", subject]) => match self.compatibility.remove(*subject) {
+                Some(compatibility) => (200, json!({ "compatibilityLevel": compatibility.as_str() })),
+                None => error(404, 40401, "Subject not found")
+            },
+            _ => error(404, 404, "HTTP 404 Not Found")
+        }
+    }
+}
+
+fn error(status: u16, error_code: u32, message: &str) -> (u16, Json) {
+    (status, json!({ "error_code": error_code, "message": message }))
+}
+
+fn schema_of(body: &Json) -> Option<Json> {
+    body["schema"].as_str().and_then(|s| serde_json::from_str(s).ok())
+}
+
+/// Whether data written with the `writer` schema can be read with the `reader` schema.
+fn can_read(reader: &Json, writer: &Json) -> bool {
+    let mut resolution = Resolution {
+        reader_names: HashMap::new(),
+        writer_names: HashMap::new(),
+        checking: HashSet::new()
+    };
+    collect_names(reader, None, &mut resolution.reader_names);
+    collect_names(writer, None, &mut resolution.writer_names);
+    resolution.can_read(reader, writer)
+}
+
+const PRIMITIVES: &[&str] = &["null", "boolean", "int", "long", "float", "double", "bytes", "string"];
+
+/// Named types of a schema, by full name and by name, to resolve the references to them.
+type Names<'a> = HashMap<String, &'a Json>;
+
+fn collect_names<'a>(schema: &'a Json, namespace: Option<&str>, names: &mut Names<'a>) {
+    match schema {
+        Json::Array(branches) => branches.iter().for_each(|branch| collect_names(branch, namespace, names)),
+        Json::Object(fields) => {
+            let mut namespace = fields.get("namespace").and_then(Json::as_str).or(namespace).map(String::from);
+            if let Some(name) = fields.get("name").and_then(Json::as_str) {
+                let full_name = match (name.rfind('.'), &namespace) {
+                    (Some(dot), _) => {
+                        namespace = Some(name[..dot].to_string());
+                        name.to_string()
+                    }
+                    (None, Some(namespace)) => format!("{}.{}", namespace, name),
+                    (None, None) => name.to_string()
+                };
+                names.entry(short_name(&full_name).to_string()).or_insert(schema);
+                names.insert(full_name, schema);
+            }
+            let namespace = namespace.as_ref().map(String::as_str);
+            for key in &["type", "items", "values"] {
+                if let Some(child) = fields.get(*key) {
+                    collect_names(child, namespace, names);
+                }
+            }
+            if let Some(Json::Array(record_fields)) = fields.get("fields") {
+                record_fields.iter().for_each(|field| collect_names(&field["type"], namespace, names));
+            }
+        }
+        _ => ()
+    }
+}
+
+fn short_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+/// Schema resolution of a reader and a writer schema, remembering the pairs of records being checked so recursive
+/// records are checked once.
+struct Resolution<'a> {
+    reader_names: Names<'a>,
+    writer_names: Names<'a>,
+    checking: HashSet<(String, String)>
+}
+
+impl<'a> Resolution<'a> {
+    fn can_read(&mut self, reader: &'a Json, writer: &'a Json) -> bool {
+        let reader = resolve(reader, &self.reader_names);
+        let writer = resolve(writer, &self.writer_names);
+        match (type_of(reader), type_of(writer)) {
+            (_, "union") => elements(writer).iter().all(|branch| self.can_read(reader, branch)),
+            ("union", _) => elements(reader).iter().any(|branch| self.can_read(branch, writer)),
+            ("record", "record") => same_name(reader, writer) && self.can_read_fields(reader, writer),
+            ("enum", "enum") => {
+                let symbols = |schema: &Json| schema["symbols"].as_array().cloned().unwrap_or_default();
+                let reader_symbols = symbols(reader);
+                same_name(reader, writer) && (reader.get("default").is_some() || symbols(writer).iter().all(|s| reader_symbols.contains(s)))
+            }
+            ("fixed", "fixed") => same_name(reader, writer) && reader["size"] == writer["size"],
+            ("array", "array") => self.can_read(&reader["items"], &writer["items"]),
+            ("map", "map") => self.can_read(&reader["values"], &writer["values"]),
+            (reader_type, writer_type) => {
+                reader_type == writer_type
+                    || match writer_type {
+                        "int" => ["long", "float", "double"].contains(&reader_type),
+                        "long" => ["float", "double"].contains(&reader_type),
+                        "float" => reader_type == "double",
+                        "string" => reader_type == "bytes",
+                        "bytes" => reader_type == "string",
+                        _ => false
+                    }
+            }
+        }
+    }
+
+    /// Every field of the reader is either in the writer, by name or by one of its aliases, with a type it can read, or
+    /// has a default. Fields only the writer has are skipped.
+    fn can_read_fields(&mut self, reader: &'a Json, writer: &'a Json) -> bool {
+        let pair = (reader["name"].to_string(), writer["name"].to_string());
+        if !self.checking.insert(pair.clone()) {
+            return true;
+        }
+        let writer_fields = elements(&writer["fields"]);
+        let readable = elements(&reader["fields"]).iter().all(|field| {
+            let aliases = elements(&field["aliases"]);
+            let written = writer_fields
+                .iter()
+                .find(|written| written["name"] == field["name"] || aliases.contains(&written["name"]));
+            match written {
+                Some(written) => self.can_read(&field["type"], &written["type"]),
+                None => field.get("default").is_some()
+            }
+        });
+        self.checking.remove(&pair);
+        readable
+    }
+}
+
+/// The named type a name refers to, or the type an object with only a type is, like `{"type": "string"}`.
+fn resolve<'a>(schema: &'a Json, names: &Names<'a>) -> &'a Json {
+    match schema {
+        Json::String(name) if !PRIMITIVES.contains(&name.as_str()) => names.get(name).cloned().unwrap_or(schema),
+        Json::Object(fields) => match fields.get("type") {
+            Some(Json::String(_)) | None => schema,
+            Some(inner) => resolve(inner, names)
+        },
+        _ => schema
+    }
+}
+
+/// Type of a resolved schema, like `record` or `long`, where logical types are the type they annotate.
+fn type_of(schema: &Json) -> &str {
+    match schema {
+        Json::Array(_) => "union",
+        Json::String(name) => name,
+        Json::Object(fields) => fields.get("type").and_then(Json::as_str).unwrap_or_default(),
+        _ => ""
+    }
+}
+
+/// Elements of an array, like the branches of a union or the fields of a record, none when it's not an array.
+fn elements(schema: &Json) -> &[Json] {
+    schema.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Named types match on their name without the namespace, like the specification says for records.
+fn same_name(reader: &Json, writer: &Json) -> bool {
+    match (reader["name"].as_str(), writer["name"].as_str()) {
+        (Some(reader_name), Some(writer_name)) => short_name(reader_name) == short_name(writer_name),
+        _ => false
+    }
+}
+
+/// Starts a stand-in with `Registry::preloaded` when `SCHEMA_REGISTRY` is `embedded`, listening on the host and port of
+/// `SCHEMA_REGISTRY_URL`. Give each service its own port when running both on one machine.
+pub fn start_from_env() -> Result<(), String> {
+    match env::var("SCHEMA_REGISTRY") {
+        Ok(ref val) if val == "embedded" => (),
+        _ => return Ok(())
+    };
+    let schema_registry_url = match env::var("SCHEMA_REGISTRY_URL") {
+        Ok(val) => val,
+        Err(_e) => "127.0.0.1:8081".to_string()
+    };
+    let address = schema_registry_url.trim_start_matches("http://").trim_end_matches('/');
+    serve(address, Registry::preloaded())?;
+    info!("Started embedded schema registry on {}", address);
+    Ok(())
+}
+
+/// Serves the registry from its own thread. The listener is bound before returning, so it can be used right away.
+/// Returns the address it listens on, which has the port picked by the system when `address` has port 0.
+pub fn serve(address: &str, registry: Registry) -> Result<SocketAddr, String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("Error binding schema registry to {}: {}", address, e))?;
+    let local_address = listener.local_addr().map_err(|e| e.to_string())?;
+    let registry = Mutex::new(registry);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_connection(stream, &registry));
+            if let Err(e) = result {
+                warn!("Error handling schema registry request: {}", e)
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref TEST_REGISTRY_URL: String = {
+        let address = serve("127.0.0.1:0", Registry::preloaded()).expect("Schema registry for the tests should start");
+        let url = address.to_string();
+        env::set_var("SCHEMA_REGISTRY_URL", &url);
+        url
+    };
+}
+
+/// Url of a preloaded registry on a free port, started on first use and shared by the tests of the process.
+/// `SCHEMA_REGISTRY_URL` is set to it, so encoders and decoders created afterwards use it.
+#[cfg(test)]
+pub fn serve_for_tests() -> &'static str {
+    &TEST_REGISTRY_URL
+}
+
+/// Handles a single request, closing the connection after the response.
+fn handle_connection(mut stream: TcpStream, registry: &Mutex<Registry>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut name_value = header.splitn(2, ':');
+        match (name_value.next().map(str::trim), name_value.next().map(str::trim)) {
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case("content-length") => content_length = value.parse().unwrap_or(0),
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case("expect") => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => ()
+        }
+    }
+    if expect_continue {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = serde_json::from_slice(&body).unwrap_or(Json::Null);
+    let (status, response) = registry.lock().expect("Schema registry lock poisoned").handle(&method, &path, &body);
+    let response = response.to_string();
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Unprocessable Entity"
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        response.len(),
+        response
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{can_read, serve, Registry};
+    use serde_json::{json, Value as Json};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn record(fields: Json) -> Json {
+        json!({ "type": "record", "name": "Transfer", "namespace": "nl.openweb.data", "fields": fields })
+    }
+
+    fn transfer() -> Json {
+        record(json!([{ "name": "id", "type": "string" }, { "name": "amount", "type": "double" }]))
+    }
+
+    fn post(registry: &mut Registry, path: &str, schema: &Json) -> (u16, Json) {
+        registry.handle("POST", path, &json!({ "schema": schema.to_string() }))
+    }
+
+    fn put_compatibility(registry: &mut Registry, subject: &str, compatibility: &str) -> (u16, Json) {
+        registry.handle("PUT", &format!("/config/{}", subject), &json!({ "compatibility": compatibility }))
+    }
+
+    #[test]
+    fn schemas_are_registered_and_looked_up_by_version_and_id() {
+        let mut registry = Registry::default();
+        let with_description = record(json!([
+            { "name": "id", "type": "string" },
+            { "name": "amount", "type": "double" },
+            { "name": "description", "type": "string", "default": "" }
+        ]));
+
+        assert_eq!(post(&mut registry, "/subjects/transfer-value/versions", &transfer()), (200, json!({ "id": 1 })));
+        assert_eq!(post(&mut registry, "/subjects/transfer-value/versions", &transfer()), (200, json!({ "id": 1 })));
+        assert_eq!(
+            post(&mut registry, "/subjects/transfer-value/versions", &with_description),
+            (200, json!({ "id": 2 }))
+        );
+        assert_eq!(post(&mut registry, "/subjects/other-value/versions", &transfer()), (200, json!({ "id": 1 })));
+
+        let (status, latest) = registry.handle("GET", "/subjects/transfer-value/versions/latest", &Json::Null);
+        assert_eq!(status, 200);
+        assert_eq!((latest["version"].clone(), latest["id"].clone()), (json!(2), json!(2)));
+        assert_eq!(
+            latest["schema"].as_str().map(|s| serde_json::from_str::<Json>(s).unwrap()),
+            Some(with_description)
+        );
+        assert_eq!(registry.handle("GET", "/subjects/transfer-value/versions", &Json::Null), (200, json!([1, 2])));
+        assert_eq!(
+            registry.handle("GET", "/subjects/transfer-value/versions/3", &Json::Null).1["error_code"],
+            40402
+        );
+        assert_eq!(
+            registry.handle("GET", "/subjects/unknown-value/versions/latest", &Json::Null).1["error_code"],
+            40401
+        );
+
+        let (status, by_id) = registry.handle("GET", "/schemas/ids/1", &Json::Null);
+        assert_eq!(status, 200);
+        assert_eq!(by_id["schema"].as_str().map(|s| serde_json::from_str::<Json>(s).unwrap()), Some(transfer()));
+        assert_eq!(registry.handle("GET", "/schemas/ids/3", &Json::Null).1["error_code"], 40403);
+        assert_eq!(registry.handle("GET", "/schemas/ids/0", &Json::Null).1["error_code"], 40403);
+    }
+
+    #[test]
+    fn incompatible_schema_is_refused_until_the_compatibility_is_none() {
+        let mut registry = Registry::default();
+        post(&mut registry, "/subjects/transfer-value/versions", &transfer());
+        let decimal = json!({ "type": "bytes", "logicalType": "decimal", "precision": 20, "scale": 2 });
+        let with_decimal = record(json!([{ "name": "id", "type": "string" }, { "name": "amount", "type": decimal }]));
+
+        let check = post(&mut registry, "/compatibility/subjects/transfer-value/versions/latest", &with_decimal);
+        assert_eq!(check, (200, json!({ "is_compatible": false })));
+        assert_eq!(post(&mut registry, "/subjects/transfer-value/versions", &with_decimal).0, 409);
+
+        assert_eq!(
+            put_compatibility(&mut registry, "transfer-value", "NONE"),
+            (200, json!({ "compatibility": "NONE" }))
+        );
+        let check = post(&mut registry, "/compatibility/subjects/transfer-value/versions/latest", &with_decimal);
+        assert_eq!(check, (200, json!({ "is_compatible": true })));
+        assert_eq!(
+            post(&mut registry, "/subjects/transfer-value/versions", &with_decimal),
+            (200, json!({ "id": 2 }))
+        );
+
+        let deleted = registry.handle("DELETE", "/config/transfer-value", &Json::Null);
+        assert_eq!(deleted, (200, json!({ "compatibilityLevel": "NONE" })));
+        assert_eq!(registry.handle("GET", "/config/transfer-value", &Json::Null).1["error_code"], 40408);
+        assert_eq!(put_compatibility(&mut registry, "transfer-value", "SOMETIMES").1["error_code"], 42203);
+    }
+
+    #[test]
+    fn levels_check_in_the_direction_they_name() {
+        let without_amount = record(json!([{ "name": "id", "type": "string" }]));
+        let with_reason = record(json!([
+            { "name": "id", "type": "string" },
+            { "name": "amount", "type": "double" },
+            { "name": "reason", "type": "string" }
+        ]));
+        let cases = [
+            ("BACKWARD", &without_amount, true),
+            ("BACKWARD", &with_reason, false),
+            ("FORWARD", &without_amount, false),
+            ("FORWARD", &with_reason, true),
+            ("FULL", &without_amount, false),
+            ("FULL", &with_reason, false)
+        ];
+        for (compatibility, schema, compatible) in &cases {
+            let mut registry = Registry::default();
+            post(&mut registry, "/subjects/transfer-value/versions", &transfer());
+            put_compatibility(&mut registry, "transfer-value", compatibility);
+            let registered = post(&mut registry, "/subjects/transfer-value/versions", schema).0 == 200;
+            assert_eq!(registered, *compatible, "{} {}", compatibility, schema);
+        }
+
+        // Only transitive levels check against versions before the latest.
+        let versions = [
+            record(json!([{ "name": "id", "type": "string" }])),
+            record(json!([{ "name": "id", "type": "string" }, { "name": "reason", "type": "string", "default": "" }]))
+        ];
+        let reason_only = record(json!([{ "name": "reason", "type": "string" }]));
+        for (compatibility, compatible) in &[("BACKWARD", true), ("BACKWARD_TRANSITIVE", false)] {
+            let mut registry = Registry::default();
+            put_compatibility(&mut registry, "transfer-value", compatibility);
+            for version in &versions {
+                assert_eq!(post(&mut registry, "/subjects/transfer-value/versions", version).0, 200);
+            }
+            let registered = post(&mut registry, "/subjects/transfer-value/versions", &reason_only).0 == 200;
+            assert_eq!(registered, *compatible, "{}", compatibility);
+        }
+    }
+
+    #[test]
+    fn schemas_resolve_like_the_avro_specification() {
+        assert!(can_read(&json!("long"), &json!("int")));
+        assert!(can_read(&json!("double"), &json!("float")));
+        assert!(can_read(&json!("bytes"), &json!("string")));
+        assert!(!can_read(&json!("int"), &json!("long")));
+        assert!(!can_read(&json!("string"), &json!("double")));
+
+        assert!(can_read(&json!(["null", "string"]), &json!("string")));
+        assert!(!can_read(&json!("string"), &json!(["null", "string"])));
+        assert!(can_read(&json!(["null", "long"]), &json!(["null", "int"])));
+
+        let status = |symbols: Json| json!({ "type": "enum", "name": "Status", "symbols": symbols });
+        assert!(can_read(&status(json!(["OPEN", "CLOSED", "BLOCKED"])), &status(json!(["OPEN", "CLOSED"]))));
+        assert!(!can_read(&status(json!(["OPEN"])), &status(json!(["OPEN", "CLOSED"]))));
+        let status_with_default = json!({ "type": "enum", "name": "Status", "symbols": ["OPEN"], "default": "OPEN" });
+        assert!(can_read(&status_with_default, &status(json!(["OPEN", "CLOSED"]))));
+
+        let fixed = |name: &str, size: u32| json!({ "type": "fixed", "name": name, "size": size });
+        assert!(can_read(&fixed("Iban", 18), &fixed("Iban", 18)));
+        assert!(!can_read(&fixed("Iban", 18), &fixed("Iban", 16)));
+        assert!(!can_read(&fixed("Iban", 18), &fixed("Token", 18)));
+
+        assert!(can_read(
+            &json!({ "type": "array", "items": "long" }),
+            &json!({ "type": "array", "items": "int" })
+        ));
+        assert!(!can_read(
+            &json!({ "type": "map", "values": "int" }),
+            &json!({ "type": "map", "values": "long" })
+        ));
+
+        let renamed = record(json!([{ "name": "transfer_id", "type": "string", "aliases": ["id"] }]));
+        assert!(can_read(&renamed, &transfer()));
+        let other_name = json!({ "type": "record", "name": "Other", "fields": [] });
+        assert!(!can_read(&other_name, &transfer()));
+    }
+
+    #[test]
+    fn named_types_are_resolved_by_reference_and_recursion_ends() {
+        let account = json!({ "type": "record", "name": "Account", "namespace": "nl.openweb.data", "fields": [{ "name": "no", "type": "string" }] });
+        let transfer = |amount: &str| {
+            record(json!([
+                { "name": "from", "type": account },
+                { "name": "to", "type": "nl.openweb.data.Account" },
+                { "name": "amount", "type": amount }
+            ]))
+        };
+        assert!(can_read(&transfer("long"), &transfer("int")));
+        assert!(!can_read(&transfer("int"), &transfer("long")));
+
+        let list = |value: &str| {
+            json!({ "type": "record", "name": "List", "fields": [
+                { "name": "value", "type": value },
+                { "name": "next", "type": ["null", "List"] }
+            ] })
+        };
+        assert!(can_read(&list("long"), &list("int")));
+        assert!(!can_read(&list("int"), &list("long")));
+    }
+
+    #[test]
+    fn registry_answers_over_http() {
+        let address = serve("127.0.0.1:0", Registry::preloaded()).expect("Registry should start");
+        let mut stream = TcpStream::connect(address).expect("Registry should accept connections");
+        stream.write_all(b"GET /schemas/ids/1 HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body: Json = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap_or_default()).expect("Body should be json");
+        assert!(body["schema"].is_string(), "{}", body);
+    }
+}
@@ -0,0 +1,173 @@
+//! Tests of the service as `main` runs it, from the commands on the memory broker through the handlers, the database
+//! and the outbox relay to the events they publish. They need the database of `DATABASE_URL_ACCOUNT`, so they're
+//! ignored by default, run them with `cargo test -- --ignored`.
+
+use crate::backend::ProducerBackend;
+use crate::config::{BackendKind, KafkaConfig};
+use crate::db::util::{get_id, valid_open_account};
+use crate::db::{self, Pool};
+use crate::embedded_registry::serve_for_tests;
+use crate::events::{AccountCreationConfirmed, AvroRecord, BalanceChanged, ConfirmAccountCreation, ConfirmMoneyTransfer, MoneyTransferConfirmed};
+use crate::kafka_consumer::{consume, CommitPolicy};
+use crate::kafka_producer::Key;
+use crate::memory_broker::testing::{dead_letters, eventually, produce, records};
+use crate::memory_broker::MemoryBroker;
+use crate::money::Money;
+use crate::shutdown::Shutdown;
+use crate::supervisor::ConsumerMonitor;
+use crate::{handlers, migrations, outbox};
+use lazy_static::lazy_static;
+use std::env;
+
+lazy_static! {
+    static ref POOL: Pool = start();
+}
+
+/// Starts the relay and the consumer once for all tests, they run until the test process ends.
+fn start() -> Pool {
+    serve_for_tests();
+    let database_url = env::var("DATABASE_URL_ACCOUNT").expect("DATABASE_URL_ACCOUNT must be set");
+    let pool = db::init_pool(&database_url);
+    migrations::run_migrations(pool.clone());
+    let mut config = KafkaConfig::default();
+    config.backend = BackendKind::Memory;
+    config.consumer.group = String::from("account-end-to-end");
+    config.consumer.workers = 2;
+    outbox::relay(pool.clone(), config.clone(), Shutdown::default());
+    let consumer_pool = pool.clone();
+    let policy = CommitPolicy::from_config(&config.consumer);
+    let monitor = ConsumerMonitor::new(&config.consumer.group);
+    consume(&config, move || handlers(&consumer_pool), policy, Shutdown::default(), monitor);
+    pool
+}
+
+fn money(s: &str) -> Money {
+    s.parse().expect("Amount should parse")
+}
+
+fn confirm_account_creation(id: &str, correlation_id: Option<String>) {
+    let command = ConfirmAccountCreation {
+        id: id.to_string(),
+        _type: String::from("AUTO"),
+        correlation_id,
+        causation_id: None
+    };
+    produce(Key::Raw(command.id.clone()), &command);
+}
+
+fn confirm_money_transfer(key: &str, id: &str, amount: &str, from: &str, to: &str) {
+    let command = ConfirmMoneyTransfer {
+        id: id.to_string(),
+        token: String::from("token"),
+        amount: money(amount),
+        from: from.to_string(),
+        to: to.to_string(),
+        description: String::from("end-to-end"),
+        correlation_id: None,
+        causation_id: None
+    };
+    produce(Key::Raw(key.to_string()), &command);
+}
+
+/// Opens an account, returning its number.
+fn open_account() -> String {
+    let id = get_id();
+    confirm_account_creation(&id, None);
+    eventually("the account creation to be confirmed", || {
+        records::<AccountCreationConfirmed>().into_iter().find(|r| r.id == id)
+    })
+    .account_no
+}
+
+fn balance_changes(id: &str, count: usize) -> Vec<BalanceChanged> {
+    eventually("the balance changes", || {
+        let changes: Vec<BalanceChanged> = records::<BalanceChanged>().into_iter().filter(|r| r.id == id).collect();
+        Some(changes).filter(|changes| changes.len() >= count)
+    })
+}
+
+#[test]
+#[ignore]
+fn account_creation_is_confirmed_continuing_the_trace_of_the_command() {
+    lazy_static::initialize(&POOL);
+    let id = get_id();
+    let correlation_id = get_id();
+    confirm_account_creation(&id, Some(correlation_id.clone()));
+
+    let confirmed = eventually("the account creation to be confirmed", || {
+        records::<AccountCreationConfirmed>().into_iter().find(|r| r.id == id)
+    });
+    assert!(
+        valid_open_account(confirmed.account_no.clone()),
+        "{} should be an open account",
+        confirmed.account_no
+    );
+    assert_eq!(confirmed.token.len(), 20);
+    assert_eq!(confirmed.correlation_id, Some(correlation_id));
+    let causation_id = confirmed.causation_id.expect("Causation id should be set");
+    assert!(
+        causation_id.starts_with(ConfirmAccountCreation::TOPIC),
+        "{} should be the position of the command",
+        causation_id
+    );
+}
+
+#[test]
+#[ignore]
+fn money_transfer_is_confirmed_and_changes_both_balances() {
+    lazy_static::initialize(&POOL);
+    let from = open_account();
+    let to = open_account();
+    let id = get_id();
+    confirm_money_transfer(&id, &id, "10.50", &from, &to);
+
+    eventually("the money transfer to be confirmed", || {
+        records::<MoneyTransferConfirmed>().into_iter().find(|r| r.id == id)
+    });
+    let changes = balance_changes(&id, 2);
+    assert_eq!(changes.len(), 2);
+    let from_change = changes.iter().find(|r| r.account_no == from).expect("Balance of from should change");
+    assert_eq!(from_change.changed_by, money("-10.50"));
+    assert_eq!(from_change.new_balance, money("-10.50"));
+    assert_eq!(from_change.from_to, to);
+    let to_change = changes.iter().find(|r| r.account_no == to).expect("Balance of to should change");
+    assert_eq!(to_change.changed_by, money("10.50"));
+    assert_eq!(to_change.new_balance, money("10.50"));
+    assert_eq!(to_change.from_to, from);
+}
+
+#[test]
+#[ignore]
+fn redelivered_money_transfer_is_handled_once() {
+    lazy_static::initialize(&POOL);
+    let to = open_account();
+    let id = get_id();
+    // With the same key the commands are handled in order, and the balance changes of an account share a key as well,
+    // so the change of the next transfer is published after any of the first.
+    confirm_money_transfer(&to, &id, "10.00", "cash", &to);
+    confirm_money_transfer(&to, &id, "10.00", "cash", &to);
+    let next = get_id();
+    confirm_money_transfer(&to, &next, "5.00", "cash", &to);
+
+    let next_change = balance_changes(&next, 1).remove(0);
+    assert_eq!(next_change.new_balance, money("15.00"));
+    assert_eq!(balance_changes(&id, 1).len(), 1);
+    assert_eq!(records::<MoneyTransferConfirmed>().iter().filter(|r| r.id == id).count(), 1);
+}
+
+#[test]
+#[ignore]
+fn command_that_is_not_avro_becomes_a_dead_letter() {
+    lazy_static::initialize(&POOL);
+    let key = get_id();
+    let mut producer = MemoryBroker::global().producer();
+    let delivery = producer
+        .send(ConfirmMoneyTransfer::TOPIC, key.as_bytes(), b"not avro")
+        .expect("Send should succeed");
+
+    let dead_letter = eventually("the dead letter", || {
+        dead_letters(ConfirmMoneyTransfer::TOPIC).into_iter().find(|d| d.key == base64::encode(&key))
+    });
+    assert_eq!((dead_letter.partition, dead_letter.offset), (delivery.partition, delivery.offset));
+    assert_eq!(dead_letter.value, base64::encode(b"not avro"));
+}
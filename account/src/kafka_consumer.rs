@@ -1,67 +1,959 @@
+use crate::backend::{Backend, ConsumerBackend, Message};
+use crate::config::{ConsumerConfig, KafkaConfig};
+use crate::correlation;
+use crate::correlation::Trace;
+use crate::dead_letter::{get_dead_letter_producer, DeadLetter, DeadLetterProducer};
+use crate::events::AvroRecord;
+use crate::kafka_producer::InFlight;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{supervise, ConsumerMonitor};
 use avro_rs::types::Value;
-use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
-use log::{info, warn};
+use chrono::{NaiveDateTime, Utc};
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use log::{error, info, warn};
 use schema_registry_converter::Decoder;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{env, thread};
 
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const ACK_WAIT: Duration = Duration::from_secs(30);
+
+/// Classifies why a message could not be processed, so `consume` knows what to do with it.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// Might succeed later, like when the database is not reachable, the message is retried with backoff.
+    Transient(String),
+    /// Will never succeed for this message, the message is moved to the dead letter topic.
+    Permanent(String),
+    /// Nothing needed to be done for this message.
+    Ignorable(String)
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::Transient(e) => write!(f, "transient error: {}", e),
+            ProcessError::Permanent(e) => write!(f, "permanent error: {}", e),
+            ProcessError::Ignorable(e) => write!(f, "ignorable: {}", e)
+        }
+    }
+}
+
+impl From<DieselError> for ProcessError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) | DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                ProcessError::Permanent(e.to_string())
+            }
+            _ => ProcessError::Transient(e.to_string())
+        }
+    }
+}
+
+impl From<PoolError> for ProcessError {
+    fn from(e: PoolError) -> Self {
+        ProcessError::Transient(e.to_string())
+    }
+}
+
+/// Key of a consumed message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKey {
+    /// Utf-8 string key, an empty string when the message has no key.
+    Raw(String),
+    /// Avro record key, in the wire format of the schema registry.
+    Avro(Vec<(String, Value)>)
+}
+
+impl MessageKey {
+    /// Keys starting with the magic byte followed by a schema id are decoded as Avro, other keys as utf-8.
+    fn decode(key: &[u8], decoder: &mut Decoder) -> Result<MessageKey, ProcessError> {
+        if key.len() > 5 && key[0] == 0 {
+            match decoder.decode(Some(key)) {
+                Ok(Value::Record(v)) => Ok(MessageKey::Avro(v)),
+                Ok(_) => Err(ProcessError::Permanent(String::from("Key is not a record, while only those expected"))),
+                Err(e) => Err(ProcessError::Permanent(format!("Error decoding key: {:?}", e)))
+            }
+        } else {
+            Ok(MessageKey::Raw(String::from_utf8_lossy(key).into_owned()))
+        }
+    }
+
+    /// Decodes an Avro key into the generated key struct, or fails when the key is a plain string.
+    pub fn record<K: AvroRecord>(&self) -> Result<K, ProcessError> {
+        match self {
+            MessageKey::Avro(values) => K::from_values(values),
+            MessageKey::Raw(v) => Err(ProcessError::Permanent(format!("Expected an Avro key for {}, got {}", K::TOPIC, v)))
+        }
+    }
+}
+
+/// Everything known about a consumed message besides its value.
+#[derive(Debug, Clone)]
+pub struct MessageContext {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: MessageKey,
+    /// Moment the message was polled. The kafka crate fetches messages in the v0 format, which has no timestamps, so
+    /// this is the closest to the time of the event there is.
+    pub received_at: NaiveDateTime,
+    /// The `correlation_id` envelope field of the value, missing for records produced before it existed.
+    pub correlation_id: Option<String>,
+    /// The `causation_id` envelope field of the value.
+    pub causation_id: Option<String>,
+    /// Records the handler emitted for the message that aren't acknowledged yet, counted by the producer they're send
+    /// with. A commit waits for them when `commit_after_acks` is set.
+    pub emitted: InFlight
+}
+
+impl MessageContext {
+    pub fn position(&self) -> String {
+        format!("{}:{}@{}", self.topic, self.partition, self.offset)
+    }
+
+    /// Trace of the records emitted while handling the message, continuing its correlation id, or starting a new one
+    /// when it has none.
+    pub fn trace(&self) -> Trace {
+        Trace {
+            correlation_id: self.correlation_id.clone().unwrap_or_else(correlation::new_id),
+            causation_id: self.position()
+        }
+    }
+}
+
 pub trait ValuesProcessor {
-    fn process(&mut self, values: &[(String, Value)]);
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError>;
 }
 
-pub fn consume(group_id: &'static str, topic: &'static str, mut values_processor: Box<dyn ValuesProcessor + Send>) -> JoinHandle<()> {
-    let brokers = match env::var("KAFKA_BROKERS") {
-        Ok(val) => val.split(',').map(String::from).collect(),
-        Err(_e) => vec!["127.0.0.1:9092".to_string()]
-    };
-    let schema_registry_url = match env::var("SCHEMA_REGISTRY_URL") {
-        Ok(val) => val,
-        Err(_e) => "127.0.0.1:8081".to_string()
-    };
+/// Creates the processors for the workers of a consumer, each worker having its own instance.
+trait ProcessorPrototype: Send {
+    fn instance(&self) -> Box<dyn ValuesProcessor + Send>;
+}
+
+impl<P: ValuesProcessor + Clone + Send + 'static> ProcessorPrototype for P {
+    fn instance(&self) -> Box<dyn ValuesProcessor + Send> {
+        Box::new(self.clone())
+    }
+}
+
+/// Processors by the topic of the records they handle, all topics are consumed by a single consumer.
+#[derive(Default)]
+pub struct Handlers {
+    prototypes: HashMap<String, Box<dyn ProcessorPrototype>>
+}
+
+impl Handlers {
+    pub fn new() -> Handlers {
+        Handlers::default()
+    }
+
+    /// Handles the records of `R` with clones of `processor`, one for each worker, subscribing to the topic of `R`.
+    pub fn register<R: AvroRecord, P: ValuesProcessor + Clone + Send + 'static>(mut self, processor: P) -> Handlers {
+        if self.prototypes.insert(R::TOPIC.to_string(), Box::new(processor)).is_some() {
+            panic!("A handler for {} is already registered", R::TOPIC)
+        }
+        self
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.prototypes.keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    fn instances(&self) -> HashMap<String, Box<dyn ValuesProcessor + Send>> {
+        self.prototypes.iter().map(|(topic, prototype)| (topic.clone(), prototype.instance())).collect()
+    }
+}
+
+struct Job {
+    topic: String,
+    partition: i32,
+    message: Message,
+    received_at: NaiveDateTime
+}
+
+struct Completion {
+    topic: String,
+    partition: i32,
+    message: Message,
+    /// Whether the message was handled, either processed, ignored or moved to the dead letter topic.
+    done: bool,
+    emitted: InFlight
+}
+
+/// What a worker reports to the consumer.
+enum WorkerEvent {
+    Completed(Completion),
+    /// The worker panicked, so the completions of the jobs it still had will never come.
+    Crashed
+}
+
+/// Tells the consumer when the worker thread it's dropped on panics.
+struct CrashReporter(Sender<WorkerEvent>);
+
+impl Drop for CrashReporter {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.send(WorkerEvent::Crashed);
+        }
+    }
+}
+
+struct Worker {
+    processors: HashMap<String, Box<dyn ValuesProcessor + Send>>,
+    decoder: Decoder,
+    /// Where messages failing permanently are stored, they're skipped without.
+    dead_letters: Option<DeadLetterProducer>,
+    shutdown: Shutdown
+}
+
+impl Worker {
+    /// Handles jobs until the consumer is done. Once a message is not done, the worker only reports the jobs it gets
+    /// after it as not done, as a later message with the same key should not be processed before it.
+    fn run(mut self, jobs: &Receiver<Job>, completions: &Sender<WorkerEvent>) {
+        let mut stopped = false;
+        for job in jobs.iter() {
+            let emitted = InFlight::default();
+            let done = !stopped && self.handle(&job, &emitted);
+            stopped = !done;
+            let completion = Completion {
+                topic: job.topic,
+                partition: job.partition,
+                message: job.message,
+                done,
+                emitted
+            };
+            if completions.send(WorkerEvent::Completed(completion)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn handle(&mut self, job: &Job, emitted: &InFlight) -> bool {
+        let m = &job.message;
+        info!("{}:{}@{}: {:?}", job.topic, job.partition, m.offset, m.value);
+        let result = match (MessageKey::decode(&m.key, &mut self.decoder), self.decoder.decode(Some(&m.value))) {
+            (Err(e), _) => Err(e),
+            (Ok(key), Ok(Value::Record(v))) => match self.processors.get_mut(&job.topic) {
+                Some(values_processor) => {
+                    let ctx = MessageContext {
+                        topic: job.topic.clone(),
+                        partition: job.partition,
+                        offset: m.offset,
+                        key,
+                        received_at: job.received_at,
+                        correlation_id: envelope_field(&v, "correlation_id"),
+                        causation_id: envelope_field(&v, "causation_id"),
+                        emitted: emitted.clone()
+                    };
+                    let shutdown = &self.shutdown;
+                    correlation::scoped(ctx.correlation_id.clone(), || process_with_retry(values_processor.as_mut(), &ctx, &v, shutdown))
+                }
+                None => Err(ProcessError::Permanent(format!("No handler registered for {}", job.topic)))
+            },
+            (Ok(_), Ok(_)) => Err(ProcessError::Permanent(String::from("Not a record, while only those expected"))),
+            (Ok(_), Err(e)) => {
+                warn!("Error decoding value of record with error: {:?}", e);
+                Err(ProcessError::Permanent(format!("Error decoding value: {:?}", e)))
+            }
+        };
+        match result {
+            Ok(()) => true,
+            Err(ProcessError::Ignorable(e)) => {
+                info!("Ignored {}:{}@{}: {}", job.topic, job.partition, m.offset, e);
+                true
+            }
+            Err(ProcessError::Permanent(e)) => match self.dead_letters.as_mut() {
+                Some(dead_letters) => {
+                    error!("Moving {}:{}@{} to dead letter topic because of {}", job.topic, job.partition, m.offset, e);
+                    let dead_letter = DeadLetter::new(&job.topic, job.partition, m, e);
+                    match dead_letters.send(&dead_letter, &m.key, &self.shutdown) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!(
+                                "Stopped storing {}:{}@{} as dead letter because of shutdown, last error: {}",
+                                job.topic, job.partition, m.offset, e
+                            );
+                            false
+                        }
+                    }
+                }
+                None => {
+                    error!("Skipping {}:{}@{} because of {}", job.topic, job.partition, m.offset, e);
+                    true
+                }
+            },
+            Err(ProcessError::Transient(e)) => {
+                warn!(
+                    "Stopped retrying {}:{}@{} because of shutdown, last error: {}",
+                    job.topic, job.partition, m.offset, e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Messages with the same key, or without a key on the same partition, go to the same worker, keeping them in order.
+fn worker_for(partition: i32, key: &[u8], workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    if key.is_empty() {
+        partition.hash(&mut hasher);
+    } else {
+        key.hash(&mut hasher);
+    }
+    hasher.finish() as usize % workers
+}
+
+/// When the offsets of handled messages are committed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitStrategy {
+    /// After every handled message, reprocessing the least after a crash.
+    PerMessage,
+    /// After all messages of a poll are handled.
+    PerBatch,
+    /// At most once per interval, for the most throughput.
+    Interval(Duration)
+}
+
+impl FromStr for CommitStrategy {
+    type Err = String;
+
+    /// Parses `message`, `batch` or `interval:<ms>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "message" => Ok(CommitStrategy::PerMessage),
+            "batch" => Ok(CommitStrategy::PerBatch),
+            v if v.starts_with("interval:") => match v["interval:".len()..].parse() {
+                Ok(ms) => Ok(CommitStrategy::Interval(Duration::from_millis(ms))),
+                Err(e) => Err(format!("Invalid commit interval in {}: {}", s, e))
+            },
+            _ => Err(format!("Unknown commit strategy {}", s))
+        }
+    }
+}
+
+/// How a consumer commits. With `after_acks` a commit first waits until the records the handlers emitted for the
+/// handled messages are acknowledged, so an event a handler emitted is never lost when the service crashes. When one of
+/// those records fails, the message it was emitted for is moved to the dead letter topic before the commit. Records
+/// send for anything else, like HTTP requests, don't hold up a commit.
+#[derive(Clone)]
+pub struct CommitPolicy {
+    pub strategy: CommitStrategy,
+    pub after_acks: bool
+}
+
+impl CommitPolicy {
+    pub fn from_config(config: &ConsumerConfig) -> CommitPolicy {
+        CommitPolicy {
+            strategy: config.commit_strategy,
+            after_acks: config.commit_after_acks
+        }
+    }
+}
+
+/// Offsets of a partition dispatched in a poll, to know up to where all messages are handled.
+#[derive(Default)]
+struct PartitionTracker {
+    offsets: VecDeque<i64>,
+    done: HashSet<i64>
+}
+
+impl PartitionTracker {
+    /// Marks the offset as handled, returns the offset up to which all messages are handled when that moved.
+    fn complete(&mut self, offset: i64) -> Option<i64> {
+        self.done.insert(offset);
+        let mut handled = None;
+        while let Some(first) = self.offsets.front().cloned() {
+            if !self.done.remove(&first) {
+                break;
+            }
+            self.offsets.pop_front();
+            handled = Some(first);
+        }
+        handled
+    }
+}
+
+/// A handled message with the records emitted for it.
+struct Unacknowledged {
+    topic: String,
+    partition: i32,
+    message: Message,
+    emitted: InFlight
+}
+
+struct Committer {
+    policy: CommitPolicy,
+    last_commit: Instant,
+    /// Whether offsets were stored since the last successful commit.
+    pending: bool,
+    /// Messages handled since the last successful commit, of which the emitted records weren't all acknowledged yet.
+    unacknowledged: Vec<Unacknowledged>,
+    /// How long a commit waits for the emitted records.
+    ack_wait: Duration,
+    /// Where messages go when a record emitted for them failed.
+    dead_letters: DeadLetterProducer,
+    shutdown: Shutdown
+}
+
+impl Committer {
+    fn new(policy: CommitPolicy, dead_letters: DeadLetterProducer, shutdown: Shutdown) -> Committer {
+        Committer {
+            policy,
+            last_commit: Instant::now(),
+            pending: false,
+            unacknowledged: Vec::new(),
+            ack_wait: ACK_WAIT,
+            dead_letters,
+            shutdown
+        }
+    }
+
+    /// Keeps a handled message with the records emitted for it, when the next commit has to wait for them.
+    fn track(&mut self, topic: &str, partition: i32, message: Message, emitted: InFlight) {
+        if self.policy.after_acks && !emitted.is_acknowledged() {
+            self.unacknowledged.push(Unacknowledged {
+                topic: topic.to_string(),
+                partition,
+                message,
+                emitted
+            });
+        }
+    }
+
+    fn store(&mut self, consumer: &mut dyn ConsumerBackend, topic: &str, partition: i32, offset: i64) {
+        match consumer.consume_message(topic, partition, offset) {
+            Ok(()) => {
+                info!("Successfully stored offset {} for {}:{} internally", offset, topic, partition);
+                self.pending = true
+            }
+            Err(e) => error!("Problem storing offset {} for {}:{}: {}", offset, topic, partition, e)
+        }
+    }
+
+    /// Commits when the strategy says so, `batch_done` tells whether all messages of the poll are handled.
+    fn maybe_commit(&mut self, consumer: &mut dyn ConsumerBackend, batch_done: bool) {
+        let due = match self.policy.strategy {
+            CommitStrategy::PerMessage => true,
+            CommitStrategy::PerBatch => batch_done,
+            CommitStrategy::Interval(interval) => self.last_commit.elapsed() >= interval
+        };
+        if due {
+            self.commit(consumer)
+        }
+    }
+
+    /// Commits the stored offsets, after the records of the handlers are acknowledged when required. A message of which
+    /// an emitted record failed is stored as dead letter first. When waiting takes too long, storing the dead letter
+    /// fails, or the commit fails, the offsets are committed with the next commit.
+    fn commit(&mut self, consumer: &mut dyn ConsumerBackend) {
+        self.last_commit = Instant::now();
+        if !self.pending {
+            return;
+        }
+        let deadline = Instant::now() + self.ack_wait;
+        let mut still_unacknowledged = Vec::new();
+        for unacknowledged in self.unacknowledged.drain(..) {
+            let Unacknowledged {
+                topic,
+                partition,
+                message,
+                ..
+            } = &unacknowledged;
+            match unacknowledged.emitted.wait_acknowledged(deadline.saturating_duration_since(Instant::now())) {
+                Some(Ok(())) => (),
+                Some(Err(e)) => {
+                    error!(
+                        "Moving {}:{}@{} to dead letter topic because a record emitted for it failed: {}",
+                        topic, partition, message.offset, e
+                    );
+                    let dead_letter = DeadLetter::new(topic, *partition, message, format!("Emitted record failed: {}", e));
+                    if let Err(e) = self.dead_letters.send(&dead_letter, &message.key, &self.shutdown) {
+                        warn!(
+                            "Stopped storing {}:{}@{} as dead letter because of shutdown, last error: {}",
+                            topic, partition, message.offset, e
+                        );
+                        still_unacknowledged.push(unacknowledged);
+                    }
+                }
+                None => still_unacknowledged.push(unacknowledged)
+            }
+        }
+        self.unacknowledged = still_unacknowledged;
+        if !self.unacknowledged.is_empty() {
+            warn!(
+                "Not committing, records emitted for {} messages are not acknowledged",
+                self.unacknowledged.len()
+            );
+            return;
+        }
+        match consumer.commit_consumed() {
+            Ok(()) => {
+                info!("Consumer offset successful committed");
+                self.pending = false
+            }
+            Err(e) => warn!("Problem committing consumer offsets, trying again with the next commit: {}", e)
+        }
+    }
+}
+
+/// Consumes the topics of the handlers created with `handlers`, with the group of `config`, until `shutdown` is
+/// triggered. The messages of a poll are processed by the configured number of workers, in order per key. Messages
+/// that can't be decoded or fail permanently are stored on `<topic>.dlq`. Offsets are committed according to `policy`,
+/// per partition up to the first message that isn't handled yet. The last commit is done after all workers are
+/// finished. When the broker can't be reached the consumer reconnects with backoff, when its thread crashes it's
+/// started again with new handlers, the state of the consumer is kept in `monitor`. Topics paused with `monitor` are
+/// not consumed, messages of the topic that are already polled are still processed.
+pub fn consume<F>(config: &KafkaConfig, handlers: F, policy: CommitPolicy, shutdown: Shutdown, monitor: ConsumerMonitor) -> JoinHandle<()>
+where
+    F: Fn() -> Handlers + Send + 'static
+{
+    let config = config.clone();
+    let consumer_shutdown = shutdown.clone();
+    let consumer_monitor = monitor.clone();
+    supervise(monitor, shutdown, move || {
+        run_consumer(&config, handlers(), policy.clone(), consumer_shutdown.clone(), consumer_monitor.clone())
+    })
+}
+
+fn run_consumer(config: &KafkaConfig, handlers: Handlers, policy: CommitPolicy, shutdown: Shutdown, monitor: ConsumerMonitor) -> JoinHandle<()> {
+    let backend = Backend::new(config);
+    let group_id = config.consumer.group.clone();
+    let workers = config.consumer.workers;
+    let poll_timeout = config.consumer.poll_timeout();
+    let schema_registry_url = schema_registry_url();
     thread::spawn(move || {
-        let mut consumer = get_consumer(brokers, group_id, topic);
-        let mut decoder = Decoder::new(schema_registry_url);
-        loop {
+        let topics = handlers.topics();
+        monitor.starting(&topics);
+        let mut subscribed: Option<Vec<String>> = None;
+        let mut consumer: Option<Box<dyn ConsumerBackend>> = None;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut committer = Committer::new(policy, get_dead_letter_producer(&backend), shutdown.clone());
+        let (completion_sender, completions) = mpsc::channel();
+        let mut job_senders = Vec::new();
+        let mut worker_handles = Vec::new();
+        for _ in 0..workers.max(1) {
+            let (job_sender, jobs) = mpsc::channel();
+            job_senders.push(job_sender);
+            let processors = handlers.instances();
+            let backend = backend.clone();
+            let schema_registry_url = schema_registry_url.clone();
+            let shutdown = shutdown.clone();
+            let completion_sender = completion_sender.clone();
+            worker_handles.push(thread::spawn(move || {
+                let _crash_reporter = CrashReporter(completion_sender.clone());
+                let worker = Worker {
+                    processors,
+                    decoder: Decoder::new(schema_registry_url),
+                    dead_letters: Some(get_dead_letter_producer(&backend)),
+                    shutdown
+                };
+                worker.run(&jobs, &completion_sender)
+            }));
+        }
+        // Only the workers hold a sender, so the channel disconnects when all of them are gone.
+        drop(completion_sender);
+        while !shutdown.is_triggered() {
+            // Paused topics are left out of the subscription, so they continue from their committed offsets.
+            let unpaused = monitor.unpaused(&topics);
+            if subscribed.as_ref() != Some(&unpaused) {
+                if let Some(c) = consumer.as_mut() {
+                    committer.commit(c.as_mut());
+                }
+                consumer = None;
+                if unpaused.is_empty() {
+                    info!("All topics are paused");
+                    monitor.running();
+                } else {
+                    info!("Subscribing to {}", unpaused.join(", "));
+                    match connect(&backend, &group_id, &unpaused, &monitor, &shutdown) {
+                        Some(c) => consumer = Some(c),
+                        None => break
+                    }
+                }
+                subscribed = Some(unpaused);
+            }
+            let consumer = match consumer.as_mut() {
+                Some(c) => c,
+                None => {
+                    thread::sleep(poll_timeout);
+                    continue;
+                }
+            };
             let mss = match consumer.poll() {
-                Ok(v) => v,
-                Err(e) => panic!("Quit because of problem doing consumer poll {}", e)
+                Ok(v) => {
+                    backoff = INITIAL_BACKOFF;
+                    v
+                }
+                Err(e) => {
+                    warn!("Reconnecting consumer in {:?} because of problem doing consumer poll {}", backoff, e);
+                    monitor.reconnecting(e);
+                    subscribed = None;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
             };
             if mss.is_empty() {
                 info!("No messages available right now.");
             };
+            let received_at = Utc::now().naive_utc();
+            let mut trackers: HashMap<(String, i32), PartitionTracker> = HashMap::new();
+            let mut dispatched = 0;
             for ms in mss.iter() {
-                for m in ms.messages() {
-                    info!("{}:{}@{}: {:?}", ms.topic(), ms.partition(), m.offset, m.value);
-                    match decoder.decode(Some(m.value)) {
-                        Ok(v) => match v {
-                            Value::Record(v) => values_processor.as_mut().process(&v),
-                            _ => panic!("Not a record, while only those expected")
-                        },
-                        Err(e) => warn!("Error decoding value of record with error: {:?}", e)
+                let tracker = trackers.entry((ms.topic.clone(), ms.partition)).or_insert_with(PartitionTracker::default);
+                for m in ms.messages.iter() {
+                    let job = Job {
+                        topic: ms.topic.clone(),
+                        partition: ms.partition,
+                        message: m.clone(),
+                        received_at
+                    };
+                    if job_senders[worker_for(ms.partition, &m.key, job_senders.len())].send(job).is_err() {
+                        panic!("Quit because a consumer worker stopped")
                     }
+                    tracker.offsets.push_back(m.offset);
+                    dispatched += 1;
                 }
-                match consumer.consume_messageset(ms) {
-                    Ok(v) => info!("Successfully stored offets internally {:#?}", v),
-                    Err(e) => panic!("Quit because of problem storing offsets {}", e)
+            }
+            for completed in 1..=dispatched {
+                // Panicking makes the supervisor restart the consumer, with new workers.
+                let completion = match completions.recv() {
+                    Ok(WorkerEvent::Completed(v)) => v,
+                    Ok(WorkerEvent::Crashed) => panic!("Quit because a consumer worker crashed"),
+                    Err(e) => panic!("Quit because the consumer workers stopped {}", e)
+                };
+                if completion.done {
+                    let Completion {
+                        topic,
+                        partition,
+                        message,
+                        emitted,
+                        ..
+                    } = completion;
+                    let offset = message.offset;
+                    committer.track(&topic, partition, message, emitted);
+                    if let Some(handled) = trackers.get_mut(&(topic.clone(), partition)).and_then(|t| t.complete(offset)) {
+                        committer.store(consumer.as_mut(), &topic, partition, handled);
+                    }
                 }
+                committer.maybe_commit(consumer.as_mut(), completed == dispatched);
+            }
+            if dispatched == 0 {
+                committer.maybe_commit(consumer.as_mut(), true);
+            }
+        }
+        drop(job_senders);
+        for handle in worker_handles {
+            handle.join().expect("Error closing consumer worker");
+        }
+        if let Some(c) = consumer.as_mut() {
+            committer.commit(c.as_mut());
+        }
+        info!("Stopped consuming {} for group {}", topics.join(", "), group_id);
+    })
+}
+
+/// Runs the handler of `topic` over the messages of that topic from the committed offsets of `group`, until a poll
+/// returns nothing, returning how many messages were handled. Offsets are committed per poll. Messages failing
+/// permanently are skipped instead of stored as dead letters, as they already were when first consumed. Meant for
+/// rebuilding a projection, using a group of its own.
+pub fn replay(config: &KafkaConfig, group: &str, handlers: &Handlers, topic: &str, shutdown: &Shutdown) -> Result<usize, String> {
+    let prototype = match handlers.prototypes.get(topic) {
+        Some(v) => v,
+        None => return Err(format!("No handler for {}, there are handlers for {}", topic, handlers.topics().join(", ")))
+    };
+    let mut processors = HashMap::new();
+    processors.insert(topic.to_string(), prototype.instance());
+    let mut worker = Worker {
+        processors,
+        decoder: Decoder::new(schema_registry_url()),
+        dead_letters: None,
+        shutdown: shutdown.clone()
+    };
+    let mut consumer = Backend::new(config).consumer(group, &[topic.to_string()])?;
+    let mut handled = 0;
+    loop {
+        let mss = consumer.poll()?;
+        if mss.is_empty() {
+            return Ok(handled);
+        }
+        let received_at = Utc::now().naive_utc();
+        for ms in mss {
+            for message in ms.messages {
+                let offset = message.offset;
+                let job = Job {
+                    topic: ms.topic.clone(),
+                    partition: ms.partition,
+                    message,
+                    received_at
+                };
+                if !worker.handle(&job, &InFlight::default()) {
+                    consumer.commit_consumed()?;
+                    return Err(format!("Stopped replaying at {}:{}@{} because of shutdown", ms.topic, ms.partition, offset));
+                }
+                consumer.consume_message(&ms.topic, ms.partition, offset)?;
+                handled += 1;
             }
-            match consumer.commit_consumed() {
-                Ok(v) => info!("Consumer offset successful committed {:#?}", v),
-                Err(e) => panic!("Quit because of problem committing consumer offsets {}", e)
-            };
         }
+        consumer.commit_consumed()?;
+    }
+}
+
+/// String value of an optional field of the record, none when the field is missing or null.
+fn envelope_field(values: &[(String, Value)], name: &str) -> Option<String> {
+    values.iter().find(|(field, _)| field == name).and_then(|(_, value)| match value {
+        Value::Union(inner) => match &**inner {
+            Value::String(v) => Some(v.clone()),
+            _ => None
+        },
+        Value::String(v) => Some(v.clone()),
+        _ => None
     })
 }
 
-fn get_consumer(brokers: Vec<String>, group: &str, topic: &str) -> Consumer {
-    match Consumer::from_hosts(brokers)
-        .with_topic(topic.to_string())
-        .with_group(group.to_string())
-        .with_fallback_offset(FetchOffset::Earliest)
-        .with_offset_storage(GroupOffsetStorage::Kafka)
-        .create()
-    {
-        Ok(c) => c,
-        Err(e) => panic!("Error creating consumer {}", e)
+/// Retries transient errors with an exponential backoff, until the processor succeeds, fails in a non transient way,
+/// or the service shuts down. In the last case the transient error is returned.
+fn process_with_retry(
+    values_processor: &mut dyn ValuesProcessor,
+    ctx: &MessageContext,
+    values: &[(String, Value)],
+    shutdown: &Shutdown
+) -> Result<(), ProcessError> {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match values_processor.process(ctx, values) {
+            Err(ProcessError::Transient(e)) if !shutdown.is_triggered() => {
+                warn!("Retrying in {:?} because of {}", backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            result => return result
+        }
+    }
+}
+
+fn schema_registry_url() -> String {
+    match env::var("SCHEMA_REGISTRY_URL") {
+        Ok(val) => val,
+        Err(_e) => "127.0.0.1:8081".to_string()
+    }
+}
+
+/// Creates the consumer, retrying with backoff while that fails. Returns `None` when `shutdown` is triggered first.
+fn connect(backend: &Backend, group: &str, topics: &[String], monitor: &ConsumerMonitor, shutdown: &Shutdown) -> Option<Box<dyn ConsumerBackend>> {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match backend.consumer(group, topics) {
+            Ok(c) => {
+                monitor.running();
+                return Some(c);
+            }
+            Err(e) if shutdown.is_triggered() => {
+                warn!("Stopped creating consumer because of shutdown, last error: {}", e);
+                return None;
+            }
+            Err(e) => {
+                warn!("Retrying creating consumer in {:?} because of {}", backoff, e);
+                monitor.reconnecting(e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{consume, worker_for, CommitPolicy, CommitStrategy, Committer, Handlers, MessageContext, PartitionTracker, ProcessError, ValuesProcessor};
+    use crate::backend::{ConsumerBackend, Message, MessageSet, ProducerBackend};
+    use crate::config::{BackendKind, KafkaConfig};
+    use crate::dead_letter::{dlq_topic, DeadLetter, DeadLetterProducer};
+    use crate::events::{AccountCreationFailed, AvroRecord};
+    use crate::kafka_producer::{InFlight, Key};
+    use crate::memory_broker::testing::{eventually, produce};
+    use crate::memory_broker::MemoryBroker;
+    use crate::shutdown::Shutdown;
+    use crate::supervisor::ConsumerMonitor;
+    use avro_rs::types::Value;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    /// Counts the commits, as the offsets themselves don't matter.
+    #[derive(Default)]
+    struct CountingConsumer {
+        commits: usize
+    }
+
+    impl ConsumerBackend for CountingConsumer {
+        fn poll(&mut self) -> Result<Vec<MessageSet>, String> {
+            Ok(Vec::new())
+        }
+
+        fn consume_message(&mut self, _topic: &str, _partition: i32, _offset: i64) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn commit_consumed(&mut self) -> Result<(), String> {
+            self.commits += 1;
+            Ok(())
+        }
+    }
+
+    struct Unreachable;
+
+    impl ProducerBackend for Unreachable {
+        fn partitions(&mut self, _topic: &str) -> Result<i32, String> {
+            Err(String::from("Broker unreachable"))
+        }
+
+        fn send_batch(&mut self, _topic: &str, _partition: i32, _records: &[(&[u8], &[u8])]) -> Result<i64, String> {
+            Err(String::from("Broker unreachable"))
+        }
+    }
+
+    fn committer(dead_letters: Box<dyn ProducerBackend>, shutdown: Shutdown) -> Committer {
+        let policy = CommitPolicy {
+            strategy: CommitStrategy::PerMessage,
+            after_acks: true
+        };
+        let mut committer = Committer::new(policy, DeadLetterProducer::new(dead_letters), shutdown);
+        committer.ack_wait = Duration::from_millis(10);
+        committer
+    }
+
+    /// Handles a message that emitted one record, returning the record in flight.
+    fn handle(committer: &mut Committer, consumer: &mut CountingConsumer) -> InFlight {
+        let emitted = InFlight::default();
+        emitted.add();
+        let message = Message {
+            offset: 7,
+            key: b"transfer-1".to_vec(),
+            value: b"value".to_vec()
+        };
+        committer.track("confirm_money_transfer", 0, message, emitted.clone());
+        committer.store(consumer, "confirm_money_transfer", 0, 7);
+        emitted
+    }
+
+    #[test]
+    fn commit_waits_until_the_emitted_records_are_acknowledged() {
+        let mut consumer = CountingConsumer::default();
+        let mut committer = committer(Box::new(Unreachable), Shutdown::default());
+        let emitted = handle(&mut committer, &mut consumer);
+
+        committer.commit(&mut consumer);
+        assert_eq!(consumer.commits, 0);
+
+        emitted.acknowledged();
+        committer.commit(&mut consumer);
+        assert_eq!(consumer.commits, 1);
+    }
+
+    #[test]
+    fn failed_send_blocks_the_commit_until_the_message_is_a_dead_letter() {
+        let mut consumer = CountingConsumer::default();
+        let shutdown = Shutdown::default();
+        shutdown.trigger();
+        let mut committer = committer(Box::new(Unreachable), shutdown);
+        let emitted = handle(&mut committer, &mut consumer);
+
+        emitted.failed(String::from("Record not accepted"));
+        committer.commit(&mut consumer);
+        committer.commit(&mut consumer);
+        assert_eq!(consumer.commits, 0);
+
+        let broker = MemoryBroker::new(1);
+        committer.dead_letters = DeadLetterProducer::new(Box::new(broker.producer()));
+        committer.commit(&mut consumer);
+        assert_eq!(consumer.commits, 1);
+        let dead_letters = broker.messages(&dlq_topic("confirm_money_transfer"), 0);
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter: DeadLetter = serde_json::from_slice(&dead_letters[0].value).expect("Dead letter should be json");
+        assert_eq!(dead_letter.offset, 7);
+        assert_eq!(dead_letter.error, "Emitted record failed: Record not accepted");
+    }
+
+    #[test]
+    fn handled_offsets_only_move_once_all_earlier_offsets_are_handled() {
+        let mut tracker = PartitionTracker::default();
+        tracker.offsets.extend(vec![3, 4, 5]);
+
+        assert_eq!(tracker.complete(4), None);
+        assert_eq!(tracker.complete(3), Some(4));
+        assert_eq!(tracker.complete(5), Some(5));
+        assert!(tracker.done.is_empty());
+    }
+
+    #[test]
+    fn messages_with_the_same_key_go_to_the_same_worker_whatever_their_partition() {
+        for key in &[&b"NL66OPEN0000000000"[..], b"transfer-1", b"a"] {
+            let worker = worker_for(0, key, 4);
+            assert!(worker < 4);
+            assert!((1..8).all(|partition| worker_for(partition, key, 4) == worker));
+        }
+        let workers: Vec<usize> = (0..8).map(|partition| worker_for(partition, b"", 4)).collect();
+        assert!((0..8).all(|partition| worker_for(partition, b"", 4) == workers[partition as usize]));
+        assert!(
+            workers.iter().any(|worker| *worker != workers[0]),
+            "Messages without a key should be spread by partition"
+        );
+    }
+
+    static CRASHED: AtomicBool = AtomicBool::new(false);
+
+    /// Panics on the first message it gets, and handles everything after that.
+    #[derive(Clone)]
+    struct CrashingOnce;
+
+    impl ValuesProcessor for CrashingOnce {
+        fn process(&mut self, _ctx: &MessageContext, _values: &[(String, Value)]) -> Result<(), ProcessError> {
+            if !CRASHED.swap(true, Ordering::SeqCst) {
+                panic!("Crashing once")
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn consumer_is_restarted_when_a_handler_panics_and_handles_the_message_again() {
+        let failed = AccountCreationFailed {
+            id: String::from("account-crash"),
+            reason: String::new(),
+            correlation_id: None,
+            causation_id: None
+        };
+        let delivery = produce(Key::Raw(failed.id.clone()), &failed);
+        let mut config = KafkaConfig::default();
+        config.backend = BackendKind::Memory;
+        config.consumer.group = String::from("crashing");
+        let shutdown = Shutdown::default();
+        let monitor = ConsumerMonitor::new("crashing");
+        let policy = CommitPolicy::from_config(&config.consumer);
+        let handlers = || Handlers::new().register::<AccountCreationFailed, _>(CrashingOnce);
+        let handle = consume(&config, handlers, policy, shutdown.clone(), monitor.clone());
+
+        let broker = MemoryBroker::global();
+        eventually("the message to be committed", || {
+            broker
+                .committed_offset("crashing", AccountCreationFailed::TOPIC, delivery.partition)
+                .filter(|committed| *committed > delivery.offset)
+        });
+        shutdown.trigger();
+        handle.join().expect("Consumer should stop");
+        assert_eq!(monitor.status().restarts, 1);
     }
 }
@@ -1,51 +1,159 @@
 use avro_rs::types::Value;
-use kafka::client::RequiredAcks;
-use kafka::producer::{Producer, Record};
-use log::info;
 use schema_registry_converter::schema_registry::SubjectNameStrategy;
 use schema_registry_converter::Encoder;
 use std::env;
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct AvroProducer {
-    producer: Producer,
-    encoder: Encoder
+/// How the schema registry subject is derived for the records of a topic, set per topic with
+/// `SUBJECT_NAME_STRATEGY_<TOPIC>`, falling back to `SUBJECT_NAME_STRATEGY` and else `topic`. With `record` or
+/// `topic_record` several record types can be send to the same topic. The services check the setting for the topics
+/// they produce to when they register their schemas at startup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubjectNameKind {
+    Topic,
+    Record,
+    TopicRecord
 }
 
-impl AvroProducer {
-    pub fn send(&mut self, topic: &str, key: String, values: Vec<(&'static str, Value)>, strategy: &SubjectNameStrategy) {
-        let value = match self.encoder.encode(values, &strategy) {
-            Ok(v) => v,
-            Err(e) => panic!("Error getting payload: {}", e)
-        };
-        let record = Record::from_key_value(topic, key, value);
-        match self.producer.send(&record) {
-            Ok(v) => info!("successfully send message {:#?}", v),
-            Err(e) => panic!("Error sending message: {}", e)
+impl SubjectNameKind {
+    pub fn for_topic(topic: &str) -> Result<SubjectNameKind, String> {
+        let topic_var = format!("SUBJECT_NAME_STRATEGY_{}", topic.to_uppercase().replace(|c: char| c == '-' || c == '.', "_"));
+        match env::var(topic_var).or_else(|_e| env::var("SUBJECT_NAME_STRATEGY")) {
+            Ok(val) => match val.to_lowercase().as_str() {
+                "topic" => Ok(SubjectNameKind::Topic),
+                "record" => Ok(SubjectNameKind::Record),
+                "topic_record" => Ok(SubjectNameKind::TopicRecord),
+                _ => Err(format!("Unknown subject name strategy {} for topic {}", val, topic))
+            },
+            Err(_e) => Ok(SubjectNameKind::Topic)
+        }
+    }
+
+    pub fn strategy(self, topic: &str, record_name: &str, is_key: bool) -> SubjectNameStrategy {
+        match self {
+            SubjectNameKind::Topic => SubjectNameStrategy::TopicNameStrategy(topic.to_string(), is_key),
+            SubjectNameKind::Record => SubjectNameStrategy::RecordNameStrategy(record_name.to_string()),
+            SubjectNameKind::TopicRecord => SubjectNameStrategy::TopicRecordNameStrategy(topic.to_string(), record_name.to_string())
+        }
+    }
+
+    /// Subject the strategy resolves to, the same way the schema registry converters do.
+    pub fn subject(self, topic: &str, record_name: &str, is_key: bool) -> String {
+        match self {
+            SubjectNameKind::Topic => format!("{}-{}", topic, if is_key { "key" } else { "value" }),
+            SubjectNameKind::Record => record_name.to_string(),
+            SubjectNameKind::TopicRecord => format!("{}-{}", topic, record_name)
+        }
+    }
+}
+
+/// Key of a record to send.
+pub enum Key {
+    /// Utf-8 string, as used by the services before Avro keys.
+    Raw(String),
+    /// Avro record, encoded with the key schema of the topic.
+    Avro {
+        record_name: &'static str,
+        values: Vec<(&'static str, Value)>
+    }
+}
+
+/// Counts the records emitted for a consumed message that aren't acknowledged yet, and keeps the error of the first one
+/// that failed, so the consumer can wait for them before committing the offset of the message.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<(Mutex<Emitted>, Condvar)>);
+
+#[derive(Debug, Default)]
+struct Emitted {
+    pending: usize,
+    failure: Option<String>
+}
+
+impl InFlight {
+    pub fn add(&self) {
+        let (emitted, _) = &*self.0;
+        emitted.lock().expect("In flight lock poisoned").pending += 1;
+    }
+
+    /// Called for each added record once the broker acknowledged it.
+    pub fn acknowledged(&self) {
+        self.settle(|_| ())
+    }
+
+    /// Called for each added record that will never be acknowledged, like when it couldn't be encoded or the broker
+    /// refused it.
+    pub fn failed(&self, error: String) {
+        self.settle(|emitted| {
+            emitted.failure.get_or_insert(error);
+        })
+    }
+
+    /// Called for an added record that wasn't queued after all, as the handler got an error for it instead.
+    pub fn forget(&self) {
+        self.settle(|_| ())
+    }
+
+    /// Whether all records are acknowledged, none failed.
+    pub fn is_acknowledged(&self) -> bool {
+        let (emitted, _) = &*self.0;
+        let emitted = emitted.lock().expect("In flight lock poisoned");
+        emitted.pending == 0 && emitted.failure.is_none()
+    }
+
+    /// Waits until no records are in flight. Returns none when that didn't happen within `timeout`, otherwise the error
+    /// of the first record that failed, if any.
+    pub fn wait_acknowledged(&self, timeout: Duration) -> Option<Result<(), String>> {
+        let deadline = Instant::now() + timeout;
+        let (emitted, changed) = &*self.0;
+        let mut emitted = emitted.lock().expect("In flight lock poisoned");
+        while emitted.pending > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            emitted = changed.wait_timeout(emitted, deadline - now).expect("In flight lock poisoned").0;
         }
+        Some(match &emitted.failure {
+            Some(e) => Err(e.clone()),
+            None => Ok(())
+        })
+    }
+
+    fn settle<F: FnOnce(&mut Emitted)>(&self, f: F) {
+        let (emitted, changed) = &*self.0;
+        let mut emitted = emitted.lock().expect("In flight lock poisoned");
+        emitted.pending = emitted.pending.saturating_sub(1);
+        f(&mut emitted);
+        changed.notify_all();
     }
 }
 
-pub fn get_producer() -> AvroProducer {
-    let brokers = match env::var("KAFKA_BROKERS") {
-        Ok(val) => val.split(',').map(String::from).collect(),
-        Err(_e) => vec!["127.0.0.1:9092".to_string()]
-    };
-    let schema_registry_url = match env::var("SCHEMA_REGISTRY_URL") {
-        Ok(val) => val,
-        Err(_e) => "127.0.0.1:8081".to_string()
-    };
-    let producer = match Producer::from_hosts(brokers)
-        .with_ack_timeout(Duration::from_secs(1))
-        .with_required_acks(RequiredAcks::All)
-        .create()
-    {
-        Ok(p) => p,
-        Err(e) => panic!("Error creating producer: {}", e)
-    };
-    let encoder = Encoder::new(schema_registry_url);
-    AvroProducer {
-        producer,
-        encoder
+/// Encodes keys and values with the schemas in the schema registry at `SCHEMA_REGISTRY_URL`.
+pub struct AvroEncoder(Encoder);
+
+impl AvroEncoder {
+    pub fn from_env() -> AvroEncoder {
+        let schema_registry_url = match env::var("SCHEMA_REGISTRY_URL") {
+            Ok(val) => val,
+            Err(_e) => "127.0.0.1:8081".to_string()
+        };
+        AvroEncoder(Encoder::new(schema_registry_url))
+    }
+
+    /// Returns the encoded key and value.
+    pub fn encode(&mut self, topic: &str, key: Key, values: Vec<(&'static str, Value)>, strategy: &SubjectNameStrategy) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let key = match key {
+            Key::Raw(v) => v.into_bytes(),
+            Key::Avro {
+                record_name,
+                values
+            } => {
+                let key_strategy = SubjectNameKind::for_topic(topic)?.strategy(topic, record_name, true);
+                self.0.encode(values, &key_strategy).map_err(|e| format!("Error getting key payload: {}", e))?
+            }
+        };
+        let value = self.0.encode(values, &strategy).map_err(|e| format!("Error getting payload: {}", e))?;
+        Ok((key, value))
     }
 }
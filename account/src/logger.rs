@@ -1,9 +1,20 @@
+use crate::correlation;
 use env_logger::Builder;
 use log::LevelFilter;
+use std::io::Write;
 
+/// Logs like env_logger does by default, with the correlation id of the thread after the module when there is one.
 pub fn setup_logger(rust_log: Option<&str>) {
     let mut builder = Builder::new();
     builder.filter(None, LevelFilter::Warn);
+    builder.format(|buf, record| {
+        let level = buf.default_styled_level(record.level());
+        let module = record.module_path().unwrap_or_default();
+        match correlation::current() {
+            Some(id) => writeln!(buf, "[{} {} {} {}] {}", buf.timestamp(), level, module, id, record.args()),
+            None => writeln!(buf, "[{} {} {}] {}", buf.timestamp(), level, module, record.args())
+        }
+    });
 
     rust_log.map(|conf| builder.parse_filters(conf));
 
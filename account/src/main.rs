@@ -16,174 +16,185 @@ extern crate serde_json;
 use dotenv::dotenv;
 use std::env;
 
+mod backend;
+mod command;
+mod config;
+mod correlation;
 mod db;
+mod dead_letter;
+mod embedded_registry;
+#[cfg(test)]
+mod end_to_end;
+mod events;
+mod inbox;
 mod kafka_consumer;
 mod kafka_producer;
 mod logger;
+mod memory_broker;
+mod money;
+mod outbox;
+mod schema_registry;
+mod shutdown;
+mod supervisor;
 
-use crate::db::models::{Balance, ConfirmedAccount};
+use crate::command::Command;
+use crate::config::KafkaConfig;
+use crate::correlation::{RequestIds, Trace};
+use crate::db::models::Balance;
+use crate::db::util::bank_code;
 
 use crate::db::DbConn;
 use crate::db::Pool;
-use crate::kafka_consumer::{consume, ValuesProcessor};
-use crate::kafka_producer::get_producer;
+use crate::events::{AccountCreationConfirmed,
+                    AccountCreationFailed,
+                    AvroRecord,
+                    BalanceChanged,
+                    BalanceChangedKey,
+                    ConfirmAccountCreation,
+                    ConfirmMoneyTransfer,
+                    MoneyTransferConfirmed,
+                    MoneyTransferFailed,
+                    OWNED_SCHEMAS};
+use crate::inbox::{process_once, InboxKey};
+use crate::kafka_consumer::{consume, CommitPolicy, Handlers, MessageContext, ProcessError, ValuesProcessor};
 use crate::logger::setup_logger;
+use crate::outbox::Emission;
+use crate::schema_registry::register_schemas;
+use crate::shutdown::{Accepting, Shutdown};
+use crate::supervisor::ConsumerMonitor;
 use avro_rs::types::Value;
+use diesel::QueryResult;
 use log::{error, info};
 use rocket::config::{Config, Environment, LoggingLevel};
 use rocket_contrib::json::Json;
-use schema_registry_converter::schema_registry::SubjectNameStrategy;
-use std::collections::HashMap;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::thread;
+use std::time::Duration;
+use std::{process, thread};
 
+/// Longest the requests in flight are waited for before exiting.
+const REQUEST_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
 struct CacContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for CacContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_cac(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let cac_event = ConfirmAccountCreation::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = cac_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_cac(ctx, cac_event, &conn))
     }
 }
 
-struct ProducerData {
-    topic: &'static str,
-    key: String,
-    values: Vec<(&'static str, Value)>
-}
-
-fn handle_cac(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
-    };
-    let _type = match &values[1] {
-        (_type, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an enum, while that was expected")
-    };
-    let cac = db::ConfirmedAccount::get_cac(id.clone(), _type, conn);
-    let key = id.clone();
-    let producer_data = match cac.reason {
-        None => ProducerData {
-            topic: "account_creation_confirmed",
+/// Creates the account and stores the resulting event in the outbox, both within the transaction of `process_once`.
+fn handle_cac(ctx: &MessageContext, cac_event: ConfirmAccountCreation, conn: &DbConn) -> Result<(), ProcessError> {
+    if db::ConfirmedAccount::find(&cac_event.id, conn)?.is_some() {
+        return Err(ProcessError::Ignorable(format!("account creation {} is already confirmed", cac_event.id)));
+    }
+    let cac = db::ConfirmedAccount::create_cac(cac_event.id.clone(), cac_event._type.clone(), conn)?;
+    let trace = ctx.trace();
+    let key = cac_event.id.clone();
+    match cac.reason {
+        None => outbox::stage(
             key,
-            values: acc_vec(&values, cac)
-        },
-        Some(v) => ProducerData {
-            topic: "account_creation_failed",
+            &AccountCreationConfirmed {
+                id: cac_event.id,
+                account_no: cac.account_no,
+                token: cac.token,
+                _type: cac_event._type,
+                correlation_id: Some(trace.correlation_id),
+                causation_id: Some(trace.causation_id)
+            },
+            conn
+        )?,
+        Some(reason) => outbox::stage(
             key,
-            values: fail_vec(&values, v)
-        }
-    };
-    sender.send(producer_data).unwrap();
-}
-
-fn acc_vec(cac_values: &[(String, Value)], cac: ConfirmedAccount) -> Vec<(&'static str, Value)> {
-    let id = match cac_values[0] {
-        (ref _id, Value::String(ref v)) => ("id", Value::String(v.clone())),
-        _ => panic!("Not an id, while that was expected")
-    };
-    let account_no = ("account_no", Value::String(cac.account_no));
-    let token = ("token", Value::String(cac.token));
-
-    let tp = match cac_values[1] {
-        (ref _type, Value::String(ref v)) => ("_type", Value::String(v.clone())),
-        _ => panic!("Not an type, while that was expected")
-    };
-
-    vec![id, account_no, token, tp]
-}
-
-fn fail_vec(cac_values: &[(String, Value)], reason: String) -> Vec<(&'static str, Value)> {
-    let id = match cac_values[0] {
-        (ref _id, Value::String(ref v)) => ("id", Value::String(v.clone())),
-        _ => panic!("Not an id, while that was expected")
+            &AccountCreationFailed {
+                id: cac_event.id,
+                reason,
+                correlation_id: Some(trace.correlation_id),
+                causation_id: Some(trace.causation_id)
+            },
+            conn
+        )?
     };
-    vec![id, ("reason", Value::String(reason))]
+    Ok(())
 }
 
+#[derive(Clone)]
 struct CmtContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for CmtContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_cmt(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let cmt_event = ConfirmMoneyTransfer::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = cmt_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_cmt(ctx, cmt_event, &conn))
     }
 }
 
-fn handle_cmt(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(v)) => v.clone(),
-        _ => panic!("Not a an id, while that was expected")
-    };
-    let (cmt, b_from, b_to) = db::ConfirmedTransaction::get_cmt(id.clone(), values, conn);
-    let key = id;
-    {
-        let producer_data = match cmt.reason {
-            None => ProducerData {
-                topic: "money_transfer_confirmed",
-                key,
-                values: mtc_vec(values)
-            },
-            Some(v) => ProducerData {
-                topic: "money_transfer_failed",
-                key,
-                values: fail_vec(values, v)
-            }
-        };
-        sender.send(producer_data).unwrap();
+/// Transfers the money and stores the resulting events in the outbox, both within the transaction of `process_once`.
+fn handle_cmt(ctx: &MessageContext, cmt_event: ConfirmMoneyTransfer, conn: &DbConn) -> Result<(), ProcessError> {
+    if db::ConfirmedTransaction::find(&cmt_event.id, conn)?.is_some() {
+        return Err(ProcessError::Ignorable(format!("money transfer {} is already confirmed", cmt_event.id)));
     }
+    let trace = ctx.trace();
+    let (cmt, b_from, b_to) = db::ConfirmedTransaction::create_cmt(
+        cmt_event.id.clone(),
+        cmt_event.amount,
+        cmt_event.from.clone(),
+        cmt_event.to.clone(),
+        ctx.received_at,
+        Some(trace.correlation_id.clone()),
+        conn
+    )?;
+    // The confirmation goes first, so the balance changes are never published without it.
+    let mut emission = Emission::new(&cmt_event.id);
+    let key = cmt_event.id.clone();
+    match cmt.reason {
+        None => emission.add(key, &MoneyTransferConfirmed {
+            id: cmt_event.id.clone(),
+            correlation_id: Some(trace.correlation_id.clone()),
+            causation_id: Some(trace.causation_id.clone())
+        })?,
+        Some(reason) => emission.add(key, &MoneyTransferFailed {
+            id: cmt_event.id.clone(),
+            reason,
+            correlation_id: Some(trace.correlation_id.clone()),
+            causation_id: Some(trace.causation_id.clone())
+        })?
+    };
     match b_from {
         None => info!("No balance -from- present, no balance_changed send"),
-        Some(v) => send_bc(true, &values, v, sender)
+        Some(v) => add_bc(&mut emission, true, &cmt_event, v, &trace)?
     }
     match b_to {
         None => info!("No balance -to- present, no balance_changed send"),
-        Some(v) => send_bc(false, &values, v, sender)
+        Some(v) => add_bc(&mut emission, false, &cmt_event, v, &trace)?
     }
+    emission.stage(conn)?;
+    Ok(())
 }
 
-fn mtc_vec(cmt_values: &[(String, Value)]) -> Vec<(&'static str, Value)> {
-    let id = match cmt_values[0] {
-        (ref _id, Value::String(ref v)) => ("id", Value::String(v.clone())),
-        _ => panic!("Not a fixed value of 16, while that was expected")
-    };
-    vec![id]
-}
-
-fn send_bc(is_from: bool, cmt_values: &[(String, Value)], balance: Balance, sender: &SyncSender<ProducerData>) {
-    let account_string = balance.account_no;
-    let account_no = ("account_no", Value::String(account_string.clone()));
-    let amount = ("new_balance", Value::Double(balance.amount));
-    let changed_by = match cmt_values[2] {
-        (ref _amount, Value::Long(ref v)) => {
-            if is_from {
-                ("changed_by", Value::Long(-*v))
-            } else {
-                ("changed_by", Value::Long(*v))
-            }
-        }
-        _ => panic!("Not a Long value, while that was expected")
-    };
-    let from_to = match cmt_values[if is_from { 4 } else { 3 }] {
-        (ref _from, Value::String(ref v)) => ("from_to", Value::String(v.clone())),
-        _ => panic!("Not a string value, while that was expected")
+fn add_bc(emission: &mut Emission, is_from: bool, cmt_event: &ConfirmMoneyTransfer, balance: Balance, trace: &Trace) -> QueryResult<()> {
+    let bc = BalanceChanged {
+        id: cmt_event.id.clone(),
+        account_no: balance.account_no.clone(),
+        new_balance: balance.amount,
+        changed_by: if is_from { -cmt_event.amount } else { cmt_event.amount },
+        from_to: if is_from { cmt_event.to.clone() } else { cmt_event.from.clone() },
+        description: cmt_event.description.clone(),
+        correlation_id: Some(trace.correlation_id.clone()),
+        causation_id: Some(trace.causation_id.clone())
     };
-    let description = match cmt_values[5] {
-        (ref _description, Value::String(ref v)) => ("description", Value::String(v.clone())),
-        _ => panic!("Not a string value, while that was expected")
+    let bc_key = BalanceChangedKey {
+        bank_code: bank_code(&balance.account_no),
+        account_no: balance.account_no
     };
-    let producer_data = ProducerData {
-        topic: "balance_changed",
-        key: account_string,
-        values: vec![account_no, amount, changed_by, from_to, description]
-    };
-    sender.send(producer_data).unwrap();
+    emission.add_with_key(&bc_key, &bc)
 }
 
 #[derive(Deserialize, Serialize)]
@@ -194,26 +205,15 @@ struct LoginData {
 }
 
 #[get("/cac")]
-fn cac(conn: DbConn, sender: State<JobSender>) -> Json<String> {
+fn cac(_accepting: Accepting, _conn: DbConn) -> Json<String> {
     Json(String::from("acc"))
 }
 
 #[get("/cmt")]
-fn cmt(conn: DbConn, sender: State<JobSender>) -> Json<String> {
+fn cmt(_accepting: Accepting, _conn: DbConn) -> Json<String> {
     Json(String::from("acc"))
 }
 
-// https://github.com/SergioBenitez/Rocket/issues/714
-use rocket::State;
-use std::ops::Deref;
-struct JobSender(SyncSender<ProducerData>);
-impl Deref for JobSender {
-    type Target = mpsc::SyncSender<ProducerData>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
 embed_migrations!("./migrations");
 #[allow(unused_imports)]
 mod migrations {
@@ -224,8 +224,7 @@ mod migrations {
     }
 }
 
-fn launch_rocket(tx: &SyncSender<ProducerData>, p: &Pool) {
-    migrations::run_migrations(p.clone());
+fn launch_rocket(p: &Pool, monitor: ConsumerMonitor, shutdown: Shutdown) {
     let config = Config::build(Environment::Development)
         .address("127.0.0.1")
         .port(8072)
@@ -234,57 +233,76 @@ fn launch_rocket(tx: &SyncSender<ProducerData>, p: &Pool) {
         .unwrap();
 
     let rocket = rocket::custom(config);
-    let rocket = rocket.mount("/v1", routes![cac, cmt]);
+    let rocket = rocket.mount("/v1", routes![cac, cmt, supervisor::status, supervisor::pause, supervisor::resume]);
     log::set_max_level(log::LevelFilter::max());
-    let rocket = rocket.manage(p.clone()).manage(JobSender(tx.clone()));
+    let rocket = rocket.manage(p.clone()).manage(monitor).manage(shutdown).attach(RequestIds);
     error!("Launch error {:#?}", rocket.launch());
 }
 
+/// Handlers of the consumed events, storing their results in the database of `pool`.
+fn handlers(pool: &Pool) -> Handlers {
+    Handlers::new()
+        .register::<ConfirmAccountCreation, _>(CacContext {
+            pool: pool.clone()
+        })
+        .register::<ConfirmMoneyTransfer, _>(CmtContext {
+            pool: pool.clone()
+        })
+}
+
 fn main() {
     setup_logger(None);
-    let group_id = "account";
-    let (tx, rx) = mpsc::sync_channel(1);
-    thread::spawn(move || send_loop(&rx));
-
     dotenv().ok();
+    let kafka_config = KafkaConfig::load("account");
+    match Command::parse(&env::args().skip(1).collect::<Vec<_>>(), &kafka_config) {
+        Ok(None) => (),
+        Ok(Some(command)) => {
+            let result = command::run(command, &kafka_config, |database_url| {
+                let pool = db::init_pool(&database_url.to_string());
+                migrations::run_migrations(pool.clone());
+                handlers(&pool)
+            });
+            match result {
+                Ok(()) => process::exit(0),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1)
+                }
+            }
+        }
+        Err(e) => {
+            error!("{}\n{}", e, command::USAGE);
+            process::exit(2)
+        }
+    }
+    if let Err(e) = embedded_registry::start_from_env().and_then(|()| register_schemas(OWNED_SCHEMAS)) {
+        panic!("Refusing to start: {}", e)
+    }
+    let shutdown = Shutdown::listen();
+    let relay_stop = Shutdown::default();
+
     let database_url = env::var("DATABASE_URL_ACCOUNT").expect("DATABASE_URL_ACCOUNT must be set");
     let pool = db::init_pool(&database_url);
+    // Before anything uses the database, so the relay, the consumer and the API never see an old schema.
+    migrations::run_migrations(pool.clone());
+    let relay_handle = outbox::relay(pool.clone(), kafka_config.clone(), relay_stop.clone());
 
-    let cac_handle = consume(
-        group_id,
-        "confirm_account_creation",
-        Box::from(CacContext {
-            sender: tx.clone(),
-            pool: pool.clone()
-        })
-    );
-    let cmt_handle = consume(
-        group_id,
-        "confirm_money_transfer",
-        Box::from(CmtContext {
-            sender: tx.clone(),
-            pool: pool.clone()
-        })
-    );
+    // Events go through the outbox, so there's nothing to wait for before committing.
+    let monitor = ConsumerMonitor::new(&kafka_config.consumer.group);
+    let consumer_pool = pool.clone();
+    let policy = CommitPolicy::from_config(&kafka_config.consumer);
+    let consumer_handle = consume(&kafka_config, move || handlers(&consumer_pool), policy, shutdown.clone(), monitor.clone());
 
-    let api_handle = thread::spawn(move || launch_rocket(&tx, &pool.clone()));
+    let api_shutdown = shutdown.clone();
+    thread::spawn(move || launch_rocket(&pool, monitor, api_shutdown));
 
-    cac_handle.join().expect_err("Error closing cac handler");
-    cmt_handle.join().expect_err("Error closing cmt handler");
-    api_handle.join().expect_err("Error closing api handler");
-}
-
-fn send_loop(receiver: &Receiver<ProducerData>) {
-    let mut producer = get_producer();
-    let mut cache = HashMap::new();
-    loop {
-        let producer_data = match receiver.recv() {
-            Ok(v) => v,
-            Err(e) => panic!("Error reading future from receiver: {}", e)
-        };
-        let strategy = cache
-            .entry(producer_data.topic)
-            .or_insert_with(|| SubjectNameStrategy::TopicNameStrategy(producer_data.topic.into(), false));
-        producer.send(producer_data.topic, producer_data.key, producer_data.values, strategy);
-    }
+    consumer_handle.join().expect("Error closing consumer");
+    // The consumer also stops when it fails, so make sure no new requests are accepted before waiting for them.
+    shutdown.trigger();
+    shutdown.wait_for_requests(REQUEST_DRAIN_TIMEOUT);
+    relay_stop.trigger();
+    relay_handle.join().expect("Error closing outbox relay");
+    // Rocket 0.4 can't be stopped once launched, exiting the process takes it down.
+    info!("Shutdown complete");
+    process::exit(0);
 }
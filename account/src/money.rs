@@ -0,0 +1,340 @@
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::data_types::PgNumeric;
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Numeric;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::ops::{Add, Neg, Sub};
+use std::str::FromStr;
+
+/// Amount of money in cents, so adding and subtracting amounts is exact. Stored as `NUMERIC` in the database, as an
+/// Avro `decimal` with scale 2 in records and as a string like `"12.34"` in json. Json numbers and Avro doubles, as
+/// used before, are read as well, rounded to whole cents. Schema registry subjects that still have a `double` for an
+/// amount are moved to the decimal with the `migrate-schemas` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, AsExpression, FromSqlRow)]
+#[sql_type = "Numeric"]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_cents(cents: i64) -> Money {
+        Money(cents)
+    }
+
+    /// Rounds to whole cents, for amounts that were stored as floating point.
+    pub fn from_f64(amount: f64) -> Result<Money, String> {
+        let cents = (amount * 100.0).round();
+        if cents.is_finite() && cents.abs() < i64::max_value() as f64 {
+            Ok(Money::from_cents(cents as i64))
+        } else {
+            Err(format!("{} is not an amount of money", amount))
+        }
+    }
+
+    /// The cents as big-endian two's complement without redundant leading bytes, as Avro encodes a decimal.
+    pub fn to_avro_bytes(self) -> Vec<u8> {
+        let bytes = self.0.to_be_bytes();
+        let sign = if self.0 < 0 { 0xff } else { 0 };
+        let mut start = 0;
+        while start < bytes.len() - 1 && bytes[start] == sign && bytes[start + 1] & 0x80 == sign & 0x80 {
+            start += 1;
+        }
+        bytes[start..].to_vec()
+    }
+
+    pub fn from_avro_bytes(bytes: &[u8]) -> Result<Money, String> {
+        if bytes.is_empty() || bytes.len() > 8 {
+            return Err(format!("Expected 1 to 8 bytes for an Avro decimal amount, got {}", bytes.len()));
+        }
+        let sign = if bytes[0] & 0x80 == 0 { 0 } else { 0xff };
+        let mut buf = [sign; 8];
+        buf[8 - bytes.len()..].copy_from_slice(bytes);
+        Ok(Money(i64::from_be_bytes(buf)))
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cents = i128::from(self.0).abs();
+        write!(f, "{}{}.{:02}", if self.0 < 0 { "-" } else { "" }, cents / 100, cents % 100)
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    /// Parses an amount with at most two decimals, like `12.34`, `-0.5` or `100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected an amount like 12.34, got {}", s);
+        let (negative, unsigned) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+        let (units, fraction) = match unsigned.find('.') {
+            Some(i) => (&unsigned[..i], &unsigned[i + 1..]),
+            None => (unsigned, "00")
+        };
+        let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        if !is_digits(units) || !is_digits(fraction) || fraction.len() > 2 {
+            return Err(invalid());
+        }
+        let units: i64 = units.parse().map_err(|_e| invalid())?;
+        let fraction: i64 = format!("{:0<2}", fraction).parse().map_err(|_e| invalid())?;
+        let cents = units.checked_mul(100).and_then(|cents| cents.checked_add(fraction)).ok_or_else(invalid)?;
+        Ok(Money(if negative { -cents } else { cents }))
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+struct MoneyVisitor;
+
+impl<'de> Visitor<'de> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an amount like \"12.34\"")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Money, E> {
+        Money::from_f64(v).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Money, E> {
+        v.checked_mul(100)
+            .map(Money::from_cents)
+            .ok_or_else(|| E::custom(format!("{} is too big an amount", v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Money, E> {
+        let v = i64::try_from(v).map_err(|_e| E::custom(format!("{} is too big an amount", v)))?;
+        self.visit_i64(v)
+    }
+}
+
+impl From<Money> for PgNumeric {
+    fn from(money: Money) -> Self {
+        let cents = i128::from(money.0).abs();
+        let mut units = cents / 100;
+        // Base 10000 digits of the units, least significant first, followed by one for the cents.
+        let mut digits = Vec::new();
+        while units > 0 {
+            digits.push((units % 10_000) as i16);
+            units /= 10_000;
+        }
+        let weight = digits.len() as i16 - 1;
+        digits.reverse();
+        if cents % 100 != 0 {
+            digits.push((cents % 100) as i16 * 100);
+        }
+        // Zero has no digits at all.
+        let (weight, scale) = (if digits.is_empty() { 0 } else { weight }, 2);
+        if money.0 < 0 {
+            PgNumeric::Negative {
+                weight,
+                scale,
+                digits
+            }
+        } else {
+            PgNumeric::Positive {
+                weight,
+                scale,
+                digits
+            }
+        }
+    }
+}
+
+impl TryFrom<PgNumeric> for Money {
+    type Error = String;
+
+    fn try_from(numeric: PgNumeric) -> Result<Self, Self::Error> {
+        let (negative, weight, digits) = match numeric {
+            PgNumeric::Positive {
+                weight,
+                digits,
+                ..
+            } => (false, weight, digits),
+            PgNumeric::Negative {
+                weight,
+                digits,
+                ..
+            } => (true, weight, digits),
+            PgNumeric::NaN => return Err(String::from("NaN is not an amount of money"))
+        };
+        let too_big = || String::from("Numeric is too big for an amount of money");
+        // The digit at index i is worth 10000^(weight - i), summed in ten thousandths, so up to the first digit after
+        // the decimal point.
+        let mut ten_thousandths: i128 = 0;
+        for position in (-1..=i32::from(weight)).rev() {
+            let digit = digits.get((i32::from(weight) - position) as usize).cloned().unwrap_or(0);
+            ten_thousandths = ten_thousandths
+                .checked_mul(10_000)
+                .and_then(|v| v.checked_add(i128::from(digit)))
+                .ok_or_else(too_big)?;
+        }
+        let skipped = (i32::from(weight) + 2).max(0) as usize;
+        if ten_thousandths % 100 != 0 || digits.iter().skip(skipped).any(|digit| *digit != 0) {
+            return Err(String::from("Numeric has more than 2 decimals, which an amount of money can't have"));
+        }
+        let cents = i64::try_from(ten_thousandths / 100).map_err(|_e| too_big())?;
+        Ok(Money(if negative { -cents } else { cents }))
+    }
+}
+
+impl ToSql<Numeric, Pg> for Money {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Numeric, Pg>::to_sql(&PgNumeric::from(*self), out)
+    }
+}
+
+impl FromSql<Numeric, Pg> for Money {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        Ok(Money::try_from(PgNumeric::from_sql(bytes)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Money;
+    use diesel::pg::data_types::PgNumeric;
+    use std::convert::TryFrom;
+
+    fn money(s: &str) -> Money {
+        s.parse().expect("Amount should parse")
+    }
+
+    #[test]
+    fn amounts_are_parsed_and_displayed_with_two_decimals() {
+        assert_eq!(money("12.34"), Money::from_cents(1234));
+        assert_eq!(money("-0.5"), Money::from_cents(-50));
+        assert_eq!(money("100"), Money::from_cents(10_000));
+        assert_eq!(money("-12.3").to_string(), "-12.30");
+        assert_eq!(Money::from_cents(-5).to_string(), "-0.05");
+        assert_eq!(Money::ZERO.to_string(), "0.00");
+        for invalid in &["", "-", "1.", ".5", "1.234", "a", "1e3", "--1", "92233720368547758.08"] {
+            assert!(invalid.parse::<Money>().is_err(), "{} should not parse", invalid);
+        }
+    }
+
+    #[test]
+    fn floating_point_amounts_are_rounded_to_whole_cents() {
+        assert_eq!(Money::from_f64(0.1 + 0.2), Ok(money("0.30")));
+        assert_eq!(Money::from_f64(19.999), Ok(money("20.00")));
+        assert_eq!(Money::from_f64(-10.004), Ok(money("-10.00")));
+        assert!(Money::from_f64(std::f64::NAN).is_err());
+        assert!(Money::from_f64(std::f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn json_amounts_are_strings_and_numbers_are_read_as_well() {
+        assert_eq!(serde_json::to_string(&money("-12.34")).unwrap(), r#""-12.34""#);
+        assert_eq!(serde_json::from_str::<Money>(r#""-12.34""#).unwrap(), money("-12.34"));
+        assert_eq!(serde_json::from_str::<Money>("10.5").unwrap(), money("10.50"));
+        assert_eq!(serde_json::from_str::<Money>("7").unwrap(), money("7"));
+    }
+
+    #[test]
+    fn avro_bytes_are_minimal_big_endian_twos_complement() {
+        let cases: &[(i64, &[u8])] = &[
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (-1, &[0xff]),
+            (127, &[0x7f]),
+            (128, &[0x00, 0x80]),
+            (-128, &[0x80]),
+            (-129, &[0xff, 0x7f]),
+            (1234, &[0x04, 0xd2])
+        ];
+        for (cents, bytes) in cases {
+            assert_eq!(Money::from_cents(*cents).to_avro_bytes(), bytes.to_vec(), "{} cents", cents);
+            assert_eq!(Money::from_avro_bytes(bytes), Ok(Money::from_cents(*cents)));
+        }
+        for cents in &[i64::max_value(), i64::min_value()] {
+            let bytes = Money::from_cents(*cents).to_avro_bytes();
+            assert_eq!(bytes.len(), 8);
+            assert_eq!(Money::from_avro_bytes(&bytes), Ok(Money::from_cents(*cents)));
+        }
+        assert!(Money::from_avro_bytes(&[]).is_err());
+        assert!(Money::from_avro_bytes(&[0; 9]).is_err());
+    }
+
+    #[test]
+    fn numerics_have_base_10000_digits_with_scale_2() {
+        assert_eq!(PgNumeric::from(money("12.34")), PgNumeric::Positive {
+            weight: 0,
+            scale: 2,
+            digits: vec![12, 3400]
+        });
+        assert_eq!(PgNumeric::from(money("-50000")), PgNumeric::Negative {
+            weight: 1,
+            scale: 2,
+            digits: vec![5, 0]
+        });
+        assert_eq!(PgNumeric::from(Money::ZERO), PgNumeric::Positive {
+            weight: 0,
+            scale: 2,
+            digits: vec![]
+        });
+        for s in &["0", "0.01", "-0.01", "10000.50", "-123456789.99", "92233720368547758.07"] {
+            assert_eq!(Money::try_from(PgNumeric::from(money(s))), Ok(money(s)));
+        }
+    }
+
+    #[test]
+    fn numerics_are_read_at_any_scale_without_more_than_two_decimals() {
+        let numeric = |weight, scale, digits: Vec<i16>| PgNumeric::Positive {
+            weight,
+            scale,
+            digits
+        };
+        assert_eq!(Money::try_from(numeric(1, 0, vec![5])), Ok(money("50000")));
+        assert_eq!(Money::try_from(numeric(0, 4, vec![1, 1200])), Ok(money("1.12")));
+        assert_eq!(Money::try_from(numeric(-1, 2, vec![500])), Ok(money("0.05")));
+        assert!(Money::try_from(numeric(-1, 3, vec![1230])).is_err());
+        assert!(Money::try_from(numeric(0, 8, vec![1, 1200, 1])).is_err());
+        assert!(Money::try_from(numeric(-2, 8, vec![5])).is_err());
+        assert!(Money::try_from(numeric(5, 0, vec![1])).is_err());
+        assert!(Money::try_from(PgNumeric::NaN).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::backend::{partition_for, Backend, ProducerBackend};
+use crate::config::KafkaConfig;
+use crate::db::models::{NewOutbox, Outbox};
+use crate::db::{DbConn, Pool};
+use crate::events::{values_from_json, AvroRecord};
+use crate::kafka_consumer::{INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::kafka_producer::{AvroEncoder, Key, SubjectNameKind};
+use crate::shutdown::Shutdown;
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::result::Error as DieselError;
+use diesel::QueryResult;
+use log::{error, info, warn};
+use schema_registry_converter::schema_registry::SubjectNameStrategy;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const BATCH_SIZE: i64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Failed attempts to encode the records of a command before they're parked.
+const MAX_ATTEMPTS: i32 = 10;
+/// How often sent records older than the retention are deleted.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+const DEFAULT_RETENTION_HOURS: i64 = 168;
+
+/// Stores `record` with a plain string key in the outbox. Call within the database transaction of the changes the
+/// record is about, so the record is published if and only if the changes are committed.
+pub fn stage<R: AvroRecord + Serialize>(key: String, record: &R, conn: &DbConn) -> QueryResult<usize> {
+    Outbox::insert(&new_outbox(key, None, record, None)?, conn)
+}
+
+/// Records emitted for one command, staged together under the id of the command. The relay only starts sending them
+/// once all of them are encoded, and sends them in the order they were added, retrying the rest of them before
+/// anything else when one fails. So add the record the others depend on first, like the confirmation of a transfer
+/// before the balance changes it caused.
+pub struct Emission {
+    command_id: String,
+    records: Vec<NewOutbox>
+}
+
+impl Emission {
+    pub fn new(command_id: &str) -> Emission {
+        Emission {
+            command_id: command_id.to_string(),
+            records: Vec::new()
+        }
+    }
+
+    /// Adds `record` with a plain string key.
+    pub fn add<R: AvroRecord + Serialize>(&mut self, key: String, record: &R) -> QueryResult<()> {
+        let new_outbox = new_outbox(key, None, record, Some(&self.command_id))?;
+        self.records.push(new_outbox);
+        Ok(())
+    }
+
+    /// Adds `record` with an Avro key.
+    pub fn add_with_key<K: AvroRecord + Serialize, R: AvroRecord + Serialize>(&mut self, key: &K, record: &R) -> QueryResult<()> {
+        let new_outbox = new_outbox(to_json(key)?, Some(K::NAME), record, Some(&self.command_id))?;
+        self.records.push(new_outbox);
+        Ok(())
+    }
+
+    /// Stores all records in the outbox with a single insert, see `stage`.
+    pub fn stage(self, conn: &DbConn) -> QueryResult<usize> {
+        Outbox::insert_all(&self.records, conn)
+    }
+}
+
+fn new_outbox<R: AvroRecord + Serialize>(record_key: String, key_name: Option<&str>, record: &R, command_id: Option<&str>) -> QueryResult<NewOutbox> {
+    Ok(NewOutbox {
+        topic: R::TOPIC.to_string(),
+        record_key,
+        key_name: key_name.map(String::from),
+        record_name: R::NAME.to_string(),
+        payload: to_json(record)?,
+        created_at: Utc::now().naive_utc(),
+        command_id: command_id.map(String::from)
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> QueryResult<String> {
+    serde_json::to_string(value).map_err(|e| DieselError::SerializationError(Box::new(e)))
+}
+
+/// Publishes the outbox on its own thread, in the order the records were stored with the records of a command kept
+/// together. The records are sent per partition in batches of up to the producer `batch_size`, and a batch is marked
+/// as sent once the broker acknowledged it. A crash between sending and marking publishes that batch again after a
+/// restart. Failures are retried with backoff. The thread ends when `stop` is
+/// triggered and nothing is left to publish, or publishing fails after `stop` was triggered. The command id only
+/// groups the records for the relay, it's not part of the published records, so consumers still get each record on
+/// its own.
+///
+/// The order is the order of `seq`, which is taken when a record is inserted, not when its transaction commits. So a
+/// record of a transaction that commits late can be published after records with a higher `seq`. Records with the
+/// same key still keep their order, as the handlers staging them lock the same rows, like the balance of an account,
+/// until they commit.
+///
+/// Records that fail to encode, like a payload that no longer matches its schema, are parked after `MAX_ATTEMPTS` so
+/// they don't hold up the rest of the outbox. A failure only counts while the command after it does encode, so an
+/// unreachable schema registry doesn't park anything. Parked records keep their `last_error`, and are published again
+/// once `parked_at` and `attempts` are reset. Sent records are deleted after `OUTBOX_RETENTION_HOURS`, a week by
+/// default.
+pub fn relay(pool: Pool, config: KafkaConfig, stop: Shutdown) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut producer = match Backend::new(&config).producer() {
+            Ok(p) => p,
+            Err(e) => panic!("Error creating producer: {}", e)
+        };
+        let mut encoder = AvroEncoder::from_env();
+        let mut strategies = HashMap::new();
+        let retention = retention();
+        let mut last_cleanup = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match publish_unsent(&pool, &mut encoder, &mut *producer, &mut strategies, config.producer.batch_size) {
+                Ok(0) if stop.is_triggered() => break,
+                Ok(0) if last_cleanup.elapsed() >= CLEANUP_INTERVAL => {
+                    match delete_sent(&pool, retention) {
+                        Ok(0) => (),
+                        Ok(n) => info!("Deleted {} sent records from the outbox", n),
+                        Err(e) => warn!("Error deleting sent records from the outbox: {}", e)
+                    }
+                    last_cleanup = Instant::now();
+                }
+                Ok(0) => thread::sleep(POLL_INTERVAL),
+                Ok(n) => {
+                    info!("Published {} records from the outbox", n);
+                    backoff = INITIAL_BACKOFF;
+                }
+                Err(e) if stop.is_triggered() => {
+                    warn!("Stopped publishing the outbox because of shutdown, last error: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Retrying the outbox in {:?} because of {}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        info!("Outbox relay stopped");
+    })
+}
+
+/// Publishes the oldest unsent records, returning how many were published.
+fn publish_unsent(
+    pool: &Pool,
+    encoder: &mut AvroEncoder,
+    producer: &mut dyn ProducerBackend,
+    strategies: &mut HashMap<(String, String), SubjectNameStrategy>,
+    batch_size: usize
+) -> Result<usize, String> {
+    let conn = DbConn(pool.get().map_err(|e| e.to_string())?);
+    let mut records = Outbox::unsent(BATCH_SIZE, &conn).map_err(|e| e.to_string())?;
+    if records.len() as i64 == BATCH_SIZE {
+        // The batch might not hold all records of its commands, the rest is loaded so a command is published whole.
+        let command_ids: BTreeSet<String> = records.iter().filter_map(|record| record.command_id.clone()).collect();
+        let loaded: BTreeSet<i64> = records.iter().map(|record| record.seq).collect();
+        let rest = Outbox::unsent_of_commands(command_ids.into_iter().collect(), &conn).map_err(|e| e.to_string())?;
+        records.extend(rest.into_iter().filter(|record| !loaded.contains(&record.seq)));
+    }
+    let groups = by_command(records);
+    let mut encoded_groups = Vec::new();
+    let mut encode_error = None;
+    for (i, group) in groups.iter().enumerate() {
+        match encode_all(group, encoder, strategies) {
+            Ok(encoded) => encoded_groups.push(group.iter().zip(encoded).map(|(record, (key, value))| (record, key, value)).collect()),
+            Err(e) => {
+                // When the next command doesn't encode either, the schema registry is the likely cause.
+                let next_encodes = groups.get(i + 1).map_or(false, |next| encode_all(next, encoder, strategies).is_ok());
+                let seqs: Vec<i64> = group.iter().map(|record| record.seq).collect();
+                if next_encodes && Outbox::record_failure(&seqs, &e, MAX_ATTEMPTS, &conn).map_err(|e| e.to_string())? {
+                    error!(
+                        "Parked records {:?} of the outbox after {} failed attempts, last error: {}",
+                        seqs, MAX_ATTEMPTS, e
+                    );
+                    continue;
+                }
+                // The commands before it are still published, the ones after it have to wait.
+                encode_error = Some(e);
+                break;
+            }
+        }
+    }
+    let mut published = 0;
+    for batch in into_batches(encoded_groups, producer, batch_size)? {
+        let records: Vec<(&[u8], &[u8])> = batch.records.iter().map(|(_record, key, value)| (&key[..], &value[..])).collect();
+        let seqs: Vec<i64> = batch.records.iter().map(|(record, _key, _value)| record.seq).collect();
+        producer
+            .send_batch(batch.topic, batch.partition, &records)
+            .map_err(|e| format!("Error sending records {:?} to partition {} of {}: {}", seqs, batch.partition, batch.topic, e))?;
+        Outbox::mark_all_sent(&seqs, &conn).map_err(|e| e.to_string())?;
+        published += seqs.len();
+    }
+    match encode_error {
+        Some(e) => Err(e),
+        None => Ok(published)
+    }
+}
+
+/// Encoded records for one partition, sent in a single request.
+struct Batch<'a> {
+    topic: &'a str,
+    partition: i32,
+    records: Vec<(&'a Outbox, Vec<u8>, Vec<u8>)>
+}
+
+/// Splits the encoded records of each command into batches per partition of at most `batch_size` records, to be sent
+/// in the order they're returned. A record is never put in a batch before the one holding the record of its command
+/// before it, so that keeps the order within each partition as well as within each command.
+fn into_batches<'a>(
+    encoded_groups: Vec<Vec<(&'a Outbox, Vec<u8>, Vec<u8>)>>,
+    producer: &mut dyn ProducerBackend,
+    batch_size: usize
+) -> Result<Vec<Batch<'a>>, String> {
+    let mut batches: Vec<Batch> = Vec::new();
+    let mut last_batch_of_partition: HashMap<(&str, i32), usize> = HashMap::new();
+    for group in encoded_groups {
+        let mut previous = 0;
+        for (record, key, value) in group {
+            let partition = partition_for(producer.partitioner(), &key, producer.partitions(&record.topic)?);
+            let index = match last_batch_of_partition.get(&(record.topic.as_str(), partition)) {
+                Some(&i) if i >= previous && batches[i].records.len() < batch_size.max(1) => i,
+                _ => {
+                    last_batch_of_partition.insert((record.topic.as_str(), partition), batches.len());
+                    batches.push(Batch {
+                        topic: &record.topic,
+                        partition,
+                        records: Vec::new()
+                    });
+                    batches.len() - 1
+                }
+            };
+            batches[index].records.push((record, key, value));
+            previous = index;
+        }
+    }
+    Ok(batches)
+}
+
+/// Deletes the records sent longer than `retention` ago.
+fn delete_sent(pool: &Pool, retention: ChronoDuration) -> Result<usize, String> {
+    let conn = DbConn(pool.get().map_err(|e| e.to_string())?);
+    Outbox::delete_sent_before(Utc::now().naive_utc() - retention, &conn).map_err(|e| e.to_string())
+}
+
+/// How long sent records are kept, from `OUTBOX_RETENTION_HOURS`.
+fn retention() -> ChronoDuration {
+    match env::var("OUTBOX_RETENTION_HOURS") {
+        Ok(val) => match val.parse() {
+            Ok(hours) => ChronoDuration::hours(hours),
+            Err(e) => panic!("Invalid value {} for OUTBOX_RETENTION_HOURS: {}", val, e)
+        },
+        Err(_e) => ChronoDuration::hours(DEFAULT_RETENTION_HOURS)
+    }
+}
+
+/// Groups the records by command in the order the first record of each command was stored, keeping the order of the
+/// records within a command. Records without a command are a group of their own.
+fn by_command(records: Vec<Outbox>) -> Vec<Vec<Outbox>> {
+    let mut groups: Vec<Vec<Outbox>> = Vec::new();
+    let mut group_of_command: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        let existing = record.command_id.as_ref().and_then(|command_id| group_of_command.get(command_id).cloned());
+        match existing {
+            Some(i) => groups[i].push(record),
+            None => {
+                if let Some(command_id) = &record.command_id {
+                    group_of_command.insert(command_id.clone(), groups.len());
+                }
+                groups.push(vec![record])
+            }
+        }
+    }
+    groups
+}
+
+fn encode_all(
+    group: &[Outbox],
+    encoder: &mut AvroEncoder,
+    strategies: &mut HashMap<(String, String), SubjectNameStrategy>
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+    group.iter().map(|record| encode(record, encoder, strategies)).collect()
+}
+
+fn encode(record: &Outbox, encoder: &mut AvroEncoder, strategies: &mut HashMap<(String, String), SubjectNameStrategy>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let (record_name, values) = values_from_json(&record.record_name, &record.payload)?;
+    let key = match &record.key_name {
+        None => Key::Raw(record.record_key.clone()),
+        Some(key_name) => {
+            let (record_name, values) = values_from_json(key_name, &record.record_key)?;
+            Key::Avro {
+                record_name,
+                values
+            }
+        }
+    };
+    let strategy = match strategies.entry((record.topic.clone(), record_name.to_string())) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(SubjectNameKind::for_topic(&record.topic)?.strategy(&record.topic, record_name, false))
+    };
+    encoder.encode(&record.topic, key, values, strategy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::by_command;
+    use crate::db::models::Outbox;
+    use chrono::Utc;
+
+    fn record(seq: i64, command_id: Option<&str>) -> Outbox {
+        Outbox {
+            seq,
+            topic: String::from("balance_changed"),
+            record_key: format!("key-{}", seq),
+            key_name: None,
+            record_name: String::from("nl.openweb.data.BalanceChanged"),
+            payload: String::from("{}"),
+            created_at: Utc::now().naive_utc(),
+            sent_at: None,
+            command_id: command_id.map(String::from),
+            attempts: 0,
+            last_error: None,
+            parked_at: None
+        }
+    }
+
+    fn seqs(groups: &[Vec<Outbox>]) -> Vec<Vec<i64>> {
+        groups.iter().map(|group| group.iter().map(|record| record.seq).collect()).collect()
+    }
+
+    #[test]
+    fn records_are_grouped_by_command_in_the_order_commands_were_stored() {
+        let records = vec![
+            record(1, Some("b")),
+            record(2, Some("a")),
+            record(3, Some("b")),
+            record(4, None),
+            record(5, Some("a")),
+            record(6, None),
+            record(7, Some("c")),
+        ];
+        assert_eq!(seqs(&by_command(records)), vec![vec![1, 3], vec![2, 5], vec![4], vec![6], vec![7]]);
+    }
+
+    #[test]
+    fn no_records_are_no_groups() {
+        assert!(by_command(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use log::{info, warn};
+use rocket::{http::Status,
+             request::{self, FromRequest},
+             Outcome,
+             Request,
+             State};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a response is given to be written after its handler returned, as Rocket writes it afterwards.
+const RESPONSE_GRACE: Duration = Duration::from_millis(100);
+
+/// Flag shared between threads that have to stop when the service is asked to terminate.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    /// HTTP requests accepted by the `Accepting` guard of which the handler didn't return yet.
+    requests: Arc<AtomicUsize>
+}
+
+impl Shutdown {
+    /// Creates a flag that is triggered on SIGINT or SIGTERM.
+    pub fn listen() -> Shutdown {
+        let shutdown = Shutdown::default();
+        let flag = shutdown.clone();
+        ctrlc::set_handler(move || {
+            warn!("Received termination signal, shutting down");
+            flag.trigger()
+        })
+        .expect("Error setting termination handler");
+        shutdown
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped, none once shutdown has started. The request
+    /// is counted before the flag is checked, so `wait_for_requests` can't miss one that is accepted.
+    fn accept(&self) -> Option<Accepting> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let accepting = Accepting(self.requests.clone());
+        if self.is_triggered() {
+            None
+        } else {
+            Some(accepting)
+        }
+    }
+
+    /// Waits until the handlers of the accepted requests returned and their responses had time to be written, for at
+    /// most `timeout`. Returns whether all requests were answered. Call it after triggering the shutdown, as new
+    /// requests are accepted until then.
+    pub fn wait_for_requests(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let requests = self.requests.load(Ordering::SeqCst);
+            if requests == 0 {
+                info!("No HTTP requests in flight");
+                thread::sleep(RESPONSE_GRACE);
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!("Stopped waiting for {} HTTP requests in flight after {:?}", requests, timeout);
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Request guard refusing new work with a 503 once shutdown has started. The request is in flight until the guard is
+/// dropped, when the handler returns.
+pub struct Accepting(Arc<AtomicUsize>);
+
+impl Drop for Accepting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Accepting {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Accepting, ()> {
+        let shutdown = request.guard::<State<Shutdown>>()?;
+        match shutdown.accept() {
+            Some(accepting) => Outcome::Success(accepting),
+            None => Outcome::Failure((Status::ServiceUnavailable, ()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shutdown;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn accepted_requests_are_waited_for_and_new_ones_refused_after_the_trigger() {
+        let shutdown = Shutdown::default();
+        let accepting = shutdown.accept().expect("Requests should be accepted before the shutdown");
+        shutdown.trigger();
+        assert!(shutdown.accept().is_none());
+        assert!(!shutdown.wait_for_requests(Duration::from_millis(50)));
+
+        let handler = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(accepting)
+        });
+        assert!(shutdown.wait_for_requests(Duration::from_secs(5)));
+        handler.join().unwrap();
+    }
+}
@@ -11,5 +11,12 @@ docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zo
 docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic confirm_account_creation
 docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic confirm_money_transfer
 docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic balance_changed
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic account_creation_confirmed.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic account_creation_failed.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic money_transfer_confirmed.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic money_transfer_failed.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic confirm_account_creation.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic confirm_money_transfer.dlq
+docker-compose exec connect kafka-topics --create --if-not-exists --zookeeper zookeeper:2181 --partitions 1 --replication-factor 1 --topic balance_changed.dlq
 
 ./connector/setup.sh
\ No newline at end of file
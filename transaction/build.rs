@@ -0,0 +1,241 @@
+//! Generates a struct implementing `AvroRecord` for every schema in `res` of both services. The file name of a schema
+//! is the topic it's used for, so `res/balance_changed.avsc` becomes `BalanceChanged` for the `balance_changed` topic.
+//! A `<topic>-key.avsc` schema is the Avro key of the topic, and becomes `<Topic>Key`. The schemas in the `res` of the
+//! service being build are listed in `OWNED_SCHEMAS`, to be registered at startup, those of both in `ALL_SCHEMAS`.
+//! `values_from_json` turns the json of any of the structs back into Avro values, by the full record name. A `bytes`
+//! decimal with scale 2 becomes `Money`.
+
+use serde_json::Value as Json;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SCHEMA_DIRS: [&str; 2] = ["../account/res", "../transaction/res"];
+const KEYWORDS: [&str; 12] = ["as", "box", "fn", "impl", "in", "loop", "match", "mod", "move", "ref", "type", "use"];
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR must be set"));
+    let owned_dir = canonical(&manifest_dir.join("res"));
+    let mut schemas = Vec::new();
+    for dir in SCHEMA_DIRS.iter() {
+        let dir = manifest_dir.join(dir);
+        let owned = canonical(&dir) == owned_dir;
+        println!("cargo:rerun-if-changed={}", dir.display());
+        let entries = match fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(e) => panic!("Error reading schema dir {}: {}", dir.display(), e)
+        };
+        for entry in entries {
+            let path = entry.expect("Error reading schema dir entry").path();
+            if path.extension().map_or(false, |e| e == "avsc") {
+                println!("cargo:rerun-if-changed={}", path.display());
+                schemas.push((path, owned));
+            }
+        }
+    }
+    schemas.sort_by_key(|(p, _)| p.file_name().map(|n| n.to_os_string()));
+    schemas.dedup_by_key(|(p, _)| p.file_name().map(|n| n.to_os_string()));
+
+    let mut out = String::from("// Generated by build.rs from res/*.avsc, don't edit.\n");
+    let mut owned_schemas = String::new();
+    let mut all_schemas = String::new();
+    let mut json_arms = String::new();
+    let mut record_names = HashSet::new();
+    for (path, owned) in schemas.iter() {
+        let (name, record_name, code) = generate(path);
+        out.push_str(&code);
+        if record_names.insert(record_name.clone()) {
+            json_arms.push_str(&format!("        \"{}\" => json_values::<{}>(json),\n", record_name, name));
+        }
+        let schema_file = format!(
+            r#"    SchemaFile {{
+        topic: <{name} as AvroRecord>::TOPIC,
+        name: <{name} as AvroRecord>::NAME,
+        schema: <{name} as AvroRecord>::SCHEMA,
+        is_key: <{name} as AvroRecord>::IS_KEY
+    }},
+"#,
+            name = name
+        );
+        if *owned {
+            owned_schemas.push_str(&schema_file);
+        }
+        all_schemas.push_str(&schema_file);
+    }
+    out.push_str(&format!(
+        "\n/// Schemas of the records this service produces.\npub const OWNED_SCHEMAS: &[SchemaFile] = &[\n{}];\n",
+        owned_schemas
+    ));
+    out.push_str(&format!(
+        "\n/// Schemas of the records of both services, ordered by file name.\npub const ALL_SCHEMAS: &[SchemaFile] = &[\n{}];\n",
+        all_schemas
+    ));
+    out.push_str(&format!(
+        r#"
+/// Avro values of the record named `name`, from the json of its generated struct.
+pub fn values_from_json(name: &str, json: &str) -> Result<(&'static str, Vec<(&'static str, Value)>), String> {{
+    match name {{
+{arms}        _ => Err(format!("No generated record named {{}}", name))
+    }}
+}}
+"#,
+        arms = json_arms
+    ));
+    let out_file = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR must be set")).join("events.rs");
+    fs::write(&out_file, out).unwrap_or_else(|e| panic!("Error writing {}: {}", out_file.display(), e));
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|e| panic!("Error resolving {}: {}", path.display(), e))
+}
+
+/// Returns the name of the generated struct and the full name of the record together with the code.
+fn generate(path: &Path) -> (String, String, String) {
+    let stem = path.file_stem().and_then(|s| s.to_str()).expect("Schema file name must be valid utf-8");
+    let (topic, is_key) = match stem.trim_end_matches("-key") {
+        topic if topic.len() < stem.len() => (topic, true),
+        topic => (topic, false)
+    };
+    let content = fs::read_to_string(path).unwrap_or_else(|e| panic!("Error reading {}: {}", path.display(), e));
+    let schema: Json = serde_json::from_str(&content).unwrap_or_else(|e| panic!("Error parsing {}: {}", path.display(), e));
+    if schema["type"] != "record" {
+        panic!("Only record schemas are supported, {} is not", path.display())
+    }
+    let fields = match schema["fields"].as_array() {
+        Some(v) => v,
+        None => panic!("No fields in {}", path.display())
+    };
+
+    let record_name = match (schema["namespace"].as_str(), schema["name"].as_str()) {
+        (Some(namespace), Some(name)) => format!("{}.{}", namespace, name),
+        (None, Some(name)) => name.to_string(),
+        (_, None) => panic!("No record name in {}", path.display())
+    };
+    let struct_name = camel_case(stem);
+    let mut declarations = String::new();
+    let mut values = String::new();
+    let mut decimal_fields = Vec::new();
+    for field in fields {
+        let name = field["name"].as_str().unwrap_or_else(|| panic!("Field without name in {}", path.display()));
+        let ident = if KEYWORDS.contains(&name) { format!("r#{}", name) } else { name.to_string() };
+        let rust_type = rust_type(&field["type"], path);
+        if rust_type.contains("Money") {
+            decimal_fields.push(format!("\"{}\"", name));
+        }
+        declarations.push_str(&format!("    pub {}: {},\n", ident, rust_type));
+        values.push_str(&format!(
+            "            (\"{}\", {}),\n",
+            name,
+            avro_value(&field["type"], &format!("self.{}", ident), false, path)
+        ));
+    }
+
+    let code = format!(
+        r#"
+/// {part} of the `{topic}` topic, generated from `{file}`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct {name} {{
+{declarations}}}
+
+impl AvroRecord for {name} {{
+    const TOPIC: &'static str = "{topic}";
+    const NAME: &'static str = "{record_name}";
+    const IS_KEY: bool = {is_key};
+    const SCHEMA: &'static str = include_str!("{path}");
+    const DECIMAL_FIELDS: &'static [&'static str] = &[{decimal_fields}];
+
+    fn to_values(&self) -> Vec<(&'static str, Value)> {{
+        vec![
+{values}        ]
+    }}
+}}
+"#,
+        part = if is_key { "Key" } else { "Value" },
+        topic = topic,
+        is_key = is_key,
+        file = path.file_name().and_then(|s| s.to_str()).unwrap_or_default(),
+        name = struct_name,
+        record_name = record_name,
+        path = canonical(path).display().to_string().replace('\\', "\\\\"),
+        declarations = declarations,
+        decimal_fields = decimal_fields.join(", "),
+        values = values
+    );
+    (struct_name, record_name, code)
+}
+
+fn camel_case(name: &str) -> String {
+    name.split(|c| c == '_' || c == '-')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new()
+            }
+        })
+        .collect()
+}
+
+/// Nullable unions, `["null", T]`, are the only unions supported.
+fn nullable_type(schema: &Json) -> Option<&Json> {
+    match schema.as_array() {
+        Some(types) if types.len() == 2 && types[0] == "null" => Some(&types[1]),
+        _ => None
+    }
+}
+
+fn rust_type(schema: &Json, path: &Path) -> String {
+    if let Some(inner) = nullable_type(schema) {
+        return format!("Option<{}>", rust_type(inner, path));
+    }
+    let type_name = match schema {
+        Json::String(v) => v.as_str(),
+        Json::Object(v) => v.get("type").and_then(|t| t.as_str()).unwrap_or_default(),
+        _ => ""
+    };
+    if schema["logicalType"] == "decimal" {
+        if type_name != "bytes" || schema["scale"] != 2 {
+            panic!(
+                "Only bytes decimals with scale 2, for amounts of money, are supported, not {} in {}",
+                schema,
+                path.display()
+            )
+        }
+        return String::from("Money");
+    }
+    match type_name {
+        "string" => String::from("String"),
+        "boolean" => String::from("bool"),
+        "int" => String::from("i32"),
+        "long" => String::from("i64"),
+        "float" => String::from("f32"),
+        "double" => String::from("f64"),
+        "bytes" => String::from("Vec<u8>"),
+        _ => panic!("Unsupported type {} in {}", schema, path.display())
+    }
+}
+
+/// Expression turning `expr` into an avro `Value`, `is_ref` tells whether `expr` is a reference or a field access.
+fn avro_value(schema: &Json, expr: &str, is_ref: bool, path: &Path) -> String {
+    if let Some(inner) = nullable_type(schema) {
+        return format!(
+            "Value::Union(Box::new(match &{} {{ Some(v) => {}, None => Value::Null }}))",
+            expr,
+            avro_value(inner, "v", true, path)
+        );
+    }
+    let copied = if is_ref { format!("*{}", expr) } else { expr.to_string() };
+    match rust_type(schema, path).as_str() {
+        "String" => format!("Value::String({}.clone())", expr),
+        "bool" => format!("Value::Boolean({})", copied),
+        "i32" => format!("Value::Int({})", copied),
+        "i64" => format!("Value::Long({})", copied),
+        "f32" => format!("Value::Float({})", copied),
+        "f64" => format!("Value::Double({})", copied),
+        "Vec<u8>" => format!("Value::Bytes({}.clone())", expr),
+        "Money" => format!("Value::Bytes({}.to_avro_bytes())", expr),
+        _ => panic!("Unsupported type {} in {}", schema, path.display())
+    }
+}
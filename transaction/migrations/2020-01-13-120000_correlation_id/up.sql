@@ -0,0 +1 @@
+alter table transactions add column correlation_id TEXT;
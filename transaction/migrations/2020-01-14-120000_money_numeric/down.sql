@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+alter table transactions
+  alter column amount type REAL,
+  alter column new_balance type REAL;
\ No newline at end of file
@@ -0,0 +1,3 @@
+alter table transactions
+  alter column amount type NUMERIC(18, 2) using round(amount::numeric, 2),
+  alter column new_balance type NUMERIC(18, 2) using round(new_balance::numeric, 2);
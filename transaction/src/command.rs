@@ -0,0 +1,106 @@
+use crate::backend::{Backend, OffsetReset};
+use crate::config::KafkaConfig;
+use crate::events::OWNED_SCHEMAS;
+use crate::kafka_consumer::{replay, Handlers};
+use crate::schema_registry::migrate_schemas;
+use crate::shutdown::Shutdown;
+use log::info;
+use std::collections::HashMap;
+
+pub const USAGE: &str = "Without arguments the service is started. Other commands, to run while the service is stopped:
+    reset-offsets --topic <topic> --to <position> [--group <group>]
+        Sets the offsets of the group, by default the configured one, for all partitions of the topic.
+    replay --topic <topic> --from <position> --database-url <url>
+        Runs the handler of the topic against a scratch database, from the position until it's caught up. Uses the
+        group of the service with a -replay suffix, events emitted by the handler are not send.
+    migrate-schemas
+        Registers the schemas of the service that are not compatible with the registered ones, like the decimal
+        amounts that replaced doubles. Stop the services using the topics first, see the README.
+A position is earliest, latest, an offset or a time like 2020-01-18T12:00:00 in UTC.";
+
+/// Maintenance command given as arguments, instead of starting the service.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    ResetOffsets { group: String, topic: String, to: OffsetReset },
+    Replay { topic: String, from: OffsetReset, database_url: String },
+    MigrateSchemas
+}
+
+impl Command {
+    /// Parses the arguments after the program name, `None` when there are none.
+    pub fn parse(args: &[String], config: &KafkaConfig) -> Result<Option<Command>, String> {
+        let (name, args) = match args.split_first() {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+        let mut options = options(args)?;
+        let command = match name.as_str() {
+            "reset-offsets" => Command::ResetOffsets {
+                group: options.remove("group").unwrap_or_else(|| config.consumer.group.clone()),
+                topic: required(&mut options, "topic")?,
+                to: required(&mut options, "to")?.parse()?
+            },
+            "replay" => Command::Replay {
+                topic: required(&mut options, "topic")?,
+                from: required(&mut options, "from")?.parse()?,
+                database_url: required(&mut options, "database-url")?
+            },
+            "migrate-schemas" => Command::MigrateSchemas,
+            _ => return Err(format!("Unknown command {}", name))
+        };
+        match options.keys().next() {
+            Some(option) => Err(format!("Unknown option --{} for {}", option, name)),
+            None => Ok(Some(command))
+        }
+    }
+}
+
+fn options(args: &[String]) -> Result<HashMap<String, String>, String> {
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("Unexpected argument {}", arg));
+        }
+        match args.next() {
+            Some(value) => options.insert(arg[2..].to_string(), value.clone()),
+            None => return Err(format!("Missing value for {}", arg))
+        };
+    }
+    Ok(options)
+}
+
+fn required(options: &mut HashMap<String, String>, name: &str) -> Result<String, String> {
+    options.remove(name).ok_or_else(|| format!("Missing --{}", name))
+}
+
+/// Runs the command, `handlers_for` creates the handlers of the service using the database at the given url.
+pub fn run<F: FnOnce(&str) -> Handlers>(command: Command, config: &KafkaConfig, handlers_for: F) -> Result<(), String> {
+    match command {
+        Command::ResetOffsets {
+            group,
+            topic,
+            to
+        } => reset_offsets(config, &group, &topic, to),
+        Command::Replay {
+            topic,
+            from,
+            database_url
+        } => {
+            let group = format!("{}-replay", config.consumer.group);
+            reset_offsets(config, &group, &topic, from)?;
+            let handlers = handlers_for(&database_url);
+            let handled = replay(config, &group, &handlers, &topic, &Shutdown::listen())?;
+            info!("Replayed {} messages of {}", handled, topic);
+            Ok(())
+        }
+        Command::MigrateSchemas => migrate_schemas(OWNED_SCHEMAS)
+    }
+}
+
+fn reset_offsets(config: &KafkaConfig, group: &str, topic: &str, to: OffsetReset) -> Result<(), String> {
+    for (partition, offset) in Backend::new(config).reset_offsets(group, topic, to)? {
+        info!("Reset offset of {} for {}:{} to {}", group, topic, partition, offset);
+    }
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use crate::kafka_consumer::CommitStrategy;
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+const DEFAULT_CONFIG_FILE: &str = "kafka.toml";
+
+/// Broker the services connect to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    /// The brokers of the configuration.
+    Kafka,
+    /// The in process `MemoryBroker::global()`, so the services can run without Kafka.
+    Memory
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kafka" => Ok(BackendKind::Kafka),
+            "memory" => Ok(BackendKind::Memory),
+            _ => Err(format!("Unknown kafka backend {}", s))
+        }
+    }
+}
+
+/// Where a group starts consuming a partition it has no committed offset for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FallbackOffset {
+    Earliest,
+    Latest
+}
+
+impl FromStr for FallbackOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "earliest" => Ok(FallbackOffset::Earliest),
+            "latest" => Ok(FallbackOffset::Latest),
+            _ => Err(format!("Unknown fallback offset {}", s))
+        }
+    }
+}
+
+/// Replicas that need to acknowledge a record before it counts as send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acks {
+    None,
+    One,
+    All
+}
+
+impl FromStr for Acks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "0" => Ok(Acks::None),
+            "one" | "1" => Ok(Acks::One),
+            "all" | "-1" => Ok(Acks::All),
+            _ => Err(format!("Unknown required acks {}", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(format!("Unknown compression {}", s))
+        }
+    }
+}
+
+/// How the partition of a record follows from its key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Partitioner {
+    /// The xxHash32 of the key, as the default partitioner of the kafka crate does.
+    Client,
+    /// The murmur2 hash of the key, as the Java client does.
+    Murmur2
+}
+
+impl FromStr for Partitioner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "client" => Ok(Partitioner::Client),
+            "murmur2" => Ok(Partitioner::Murmur2),
+            _ => Err(format!("Unknown partitioner {}", s))
+        }
+    }
+}
+
+/// Settings of the consumers and producers of a service. Read from the toml file at `KAFKA_CONFIG`, or `kafka.toml` in
+/// the working directory when it exists, with every setting optional. Each setting can be overridden with the
+/// environment variable mentioned on it. Topics are not configured, they follow from the registered handlers and the
+/// records that are send.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// `kafka` or `memory`, `KAFKA_BACKEND`, by default `kafka`.
+    #[serde(deserialize_with = "parsed")]
+    pub backend: BackendKind,
+    /// `KAFKA_BROKERS` as a comma separated list, by default `127.0.0.1:9092`.
+    pub brokers: Vec<String>,
+    /// `KAFKA_CLIENT_ID`, by default the name of the service.
+    pub client_id: String,
+    pub consumer: ConsumerConfig,
+    pub producer: ProducerConfig
+}
+
+/// The `[consumer]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumerConfig {
+    /// `KAFKA_GROUP_ID`, by default the name of the service.
+    pub group: String,
+    /// `earliest` or `latest`, `KAFKA_FALLBACK_OFFSET`, by default `earliest`.
+    #[serde(deserialize_with = "parsed")]
+    pub fallback_offset: FallbackOffset,
+    /// `KAFKA_FETCH_MIN_BYTES`, by default 1.
+    pub fetch_min_bytes: i32,
+    /// `KAFKA_FETCH_MAX_BYTES_PER_PARTITION`, by default 32 KiB. Bigger messages are fetched by retrying with more.
+    pub fetch_max_bytes_per_partition: i32,
+    /// How long a poll waits for messages when there are none, `KAFKA_POLL_TIMEOUT_MS`, by default 100.
+    pub poll_timeout_ms: u64,
+    /// Workers processing the polled messages, `CONSUMER_WORKERS`, by default 1.
+    pub workers: usize,
+    /// `message`, `batch` or `interval:<ms>`, `COMMIT_STRATEGY`, by default `batch`.
+    #[serde(deserialize_with = "parsed")]
+    pub commit_strategy: CommitStrategy,
+    /// Whether a commit waits for the records of the handlers to be acknowledged, `COMMIT_AFTER_ACKS`, by default
+    /// `false`.
+    pub commit_after_acks: bool
+}
+
+/// The `[producer]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProducerConfig {
+    /// `none`, `one` or `all`, `KAFKA_REQUIRED_ACKS`, by default `all`.
+    #[serde(deserialize_with = "parsed")]
+    pub required_acks: Acks,
+    /// `KAFKA_ACK_TIMEOUT_MS`, by default 1000.
+    pub ack_timeout_ms: u64,
+    /// `none`, `gzip` or `snappy`, `KAFKA_COMPRESSION`, by default `none`.
+    #[serde(deserialize_with = "parsed")]
+    pub compression: Compression,
+    /// Records that can be queued for the producer before new ones are refused, `PRODUCER_QUEUE_SIZE`, by default 100.
+    pub queue_size: usize,
+    /// How long a queued record may take to be acknowledged, `PRODUCER_DELIVERY_TIMEOUT_MS`, by default 5000.
+    pub delivery_timeout_ms: u64,
+    /// Records per partition that are send in one request, `PRODUCER_BATCH_SIZE`, by default 100.
+    pub batch_size: usize,
+    /// How long a record waits for others to share its batch, `PRODUCER_LINGER_MS`, by default 5.
+    pub linger_ms: u64,
+    /// Workers encoding the queued records, `PRODUCER_ENCODER_WORKERS`, by default 1.
+    pub encoder_workers: usize,
+    /// `client` or `murmur2`, `PRODUCER_PARTITIONER`, by default `client`. Switching moves keys to other partitions, so
+    /// drain the topics first, see the README.
+    #[serde(deserialize_with = "parsed")]
+    pub partitioner: Partitioner
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            backend: BackendKind::Kafka,
+            brokers: vec!["127.0.0.1:9092".to_string()],
+            client_id: String::new(),
+            consumer: ConsumerConfig::default(),
+            producer: ProducerConfig::default()
+        }
+    }
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        ConsumerConfig {
+            group: String::new(),
+            fallback_offset: FallbackOffset::Earliest,
+            fetch_min_bytes: 1,
+            fetch_max_bytes_per_partition: 32 * 1024,
+            poll_timeout_ms: 100,
+            workers: 1,
+            commit_strategy: CommitStrategy::PerBatch,
+            commit_after_acks: false
+        }
+    }
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        ProducerConfig {
+            required_acks: Acks::All,
+            ack_timeout_ms: 1000,
+            compression: Compression::None,
+            queue_size: 100,
+            delivery_timeout_ms: 5000,
+            batch_size: 100,
+            linger_ms: 5,
+            encoder_workers: 1,
+            partitioner: Partitioner::Client
+        }
+    }
+}
+
+impl KafkaConfig {
+    /// Loads the configuration, using `service` as group and client id when those are not set.
+    pub fn load(service: &str) -> KafkaConfig {
+        let mut config = match env::var("KAFKA_CONFIG") {
+            Ok(path) => KafkaConfig::from_file(&path),
+            Err(_e) if Path::new(DEFAULT_CONFIG_FILE).exists() => KafkaConfig::from_file(DEFAULT_CONFIG_FILE),
+            Err(_e) => KafkaConfig::default()
+        };
+        if config.client_id.is_empty() {
+            config.client_id = service.to_string();
+        }
+        if config.consumer.group.is_empty() {
+            config.consumer.group = service.to_string();
+        }
+        config.override_from_env();
+        config
+    }
+
+    fn from_file(path: &str) -> KafkaConfig {
+        let content = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => panic!("Error reading kafka config {}: {}", path, e)
+        };
+        match toml::from_str(&content) {
+            Ok(v) => v,
+            Err(e) => panic!("Error parsing kafka config {}: {}", path, e)
+        }
+    }
+
+    fn override_from_env(&mut self) {
+        override_from_env("KAFKA_BACKEND", &mut self.backend);
+        if let Ok(val) = env::var("KAFKA_BROKERS") {
+            self.brokers = val.split(',').map(String::from).collect();
+        }
+        override_from_env("KAFKA_CLIENT_ID", &mut self.client_id);
+        let consumer = &mut self.consumer;
+        override_from_env("KAFKA_GROUP_ID", &mut consumer.group);
+        override_from_env("KAFKA_FALLBACK_OFFSET", &mut consumer.fallback_offset);
+        override_from_env("KAFKA_FETCH_MIN_BYTES", &mut consumer.fetch_min_bytes);
+        override_from_env("KAFKA_FETCH_MAX_BYTES_PER_PARTITION", &mut consumer.fetch_max_bytes_per_partition);
+        override_from_env("KAFKA_POLL_TIMEOUT_MS", &mut consumer.poll_timeout_ms);
+        override_from_env("CONSUMER_WORKERS", &mut consumer.workers);
+        override_from_env("COMMIT_STRATEGY", &mut consumer.commit_strategy);
+        override_from_env("COMMIT_AFTER_ACKS", &mut consumer.commit_after_acks);
+        let producer = &mut self.producer;
+        override_from_env("KAFKA_REQUIRED_ACKS", &mut producer.required_acks);
+        override_from_env("KAFKA_ACK_TIMEOUT_MS", &mut producer.ack_timeout_ms);
+        override_from_env("KAFKA_COMPRESSION", &mut producer.compression);
+        override_from_env("PRODUCER_QUEUE_SIZE", &mut producer.queue_size);
+        override_from_env("PRODUCER_DELIVERY_TIMEOUT_MS", &mut producer.delivery_timeout_ms);
+        override_from_env("PRODUCER_BATCH_SIZE", &mut producer.batch_size);
+        override_from_env("PRODUCER_LINGER_MS", &mut producer.linger_ms);
+        override_from_env("PRODUCER_ENCODER_WORKERS", &mut producer.encoder_workers);
+        override_from_env("PRODUCER_PARTITIONER", &mut producer.partitioner);
+    }
+}
+
+impl ConsumerConfig {
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_millis(self.poll_timeout_ms)
+    }
+}
+
+impl ProducerConfig {
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+
+    pub fn delivery_timeout(&self) -> Duration {
+        Duration::from_millis(self.delivery_timeout_ms)
+    }
+
+    pub fn linger(&self) -> Duration {
+        Duration::from_millis(self.linger_ms)
+    }
+}
+
+fn override_from_env<T: FromStr>(name: &str, value: &mut T)
+where
+    T::Err: Display
+{
+    if let Ok(val) = env::var(name) {
+        *value = match val.parse() {
+            Ok(v) => v,
+            Err(e) => panic!("Invalid value {} for {}: {}", val, name, e)
+        }
+    }
+}
+
+/// Deserializes a setting from a string, the same way it's parsed from the environment.
+fn parsed<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error>
+where
+    T::Err: Display
+{
+    let val = String::deserialize(deserializer)?;
+    val.parse().map_err(serde::de::Error::custom)
+}
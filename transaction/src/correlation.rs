@@ -0,0 +1,95 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Data, Outcome, Response};
+use std::cell::RefCell;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest request id taken over from a client, longer ones are replaced by a new id.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Correlation id of what the thread is working on, added to the log lines of the thread.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Runs `f` with `correlation_id` as the current one, restoring the previous one afterwards.
+pub fn scoped<T, F: FnOnce() -> T>(correlation_id: Option<String>, f: F) -> T {
+    let previous = CURRENT.with(|current| current.replace(correlation_id));
+    let result = f();
+    CURRENT.with(|current| current.replace(previous));
+    result
+}
+
+pub fn new_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Ids for the envelope fields of an emitted record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// Id of the request everything followed from, the same for every record emitted for it in both services.
+    pub correlation_id: String,
+    /// Id of the request, or position of the message, the record is emitted for.
+    pub causation_id: String
+}
+
+/// Id of the HTTP request, from the `X-Request-Id` header of the client or new when there is none. The `RequestIds`
+/// fairing returns it in the same header, and makes it the current correlation id while the request is handled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Trace of the records emitted for the request, which are caused by the request itself.
+    pub fn trace(&self) -> Trace {
+        Trace {
+            correlation_id: self.0.clone(),
+            causation_id: self.0.clone()
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(request_id(request).clone())
+    }
+}
+
+/// The id is assigned once per request, so the guard and the fairing get the same one.
+fn request_id<'a>(request: &'a Request) -> &'a RequestId {
+    request.local_cache(|| {
+        let given = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        RequestId(given.map_or_else(new_id, String::from))
+    })
+}
+
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _data: &Data) {
+        let id = request_id(request).0.clone();
+        CURRENT.with(|current| current.replace(Some(id)));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id(request).0.clone()));
+        CURRENT.with(|current| current.replace(None));
+    }
+}
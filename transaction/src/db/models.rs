@@ -3,6 +3,7 @@ use chrono::{NaiveDateTime, Utc};
 use crate::db::schema::*;
 use crate::db::util::*;
 use crate::db::DbConn;
+use crate::money::Money;
 // use avro_rs::types::Value;
 use diesel::{self, prelude::*};
 
@@ -12,14 +13,16 @@ use diesel::{self, prelude::*};
 pub struct Transactions {
     pub id: String,
     pub account_no: String,
-    pub amount: f64,
-    pub new_balance: f64,
+    pub amount: Money,
+    pub new_balance: Money,
     pub account_type: String,
     pub changed_by: String,
     pub from_to: String,
     pub direction: String,
     pub description: String,
-    pub created_at: NaiveDateTime
+    pub created_at: NaiveDateTime,
+    /// Correlation id of the request the change followed from.
+    pub correlation_id: Option<String>
 }
 
 impl Transactions {
@@ -29,22 +32,20 @@ impl Transactions {
         Self {
             id: get_id(),
             account_no: account_no,
-            amount: 0.0,
-            new_balance: 0.0,
+            amount: Money::ZERO,
+            new_balance: Money::ZERO,
             account_type: String::new(),
             changed_by: String::new(),
             from_to: String::new(),
             direction: String::new(),
             description: String::new(),
-            created_at: now
+            created_at: now,
+            correlation_id: None
         }
     }
 
-    pub fn insert_transaction(tx: Transactions, conn: &DbConn) -> Transactions {
-        diesel::insert_into(transactions::table)
-            .values(&tx)
-            .get_result(&**conn)
-            .expect("Error saving new transaction")
+    pub fn insert_transaction(tx: Transactions, conn: &DbConn) -> QueryResult<Transactions> {
+        diesel::insert_into(transactions::table).values(&tx).get_result(&**conn)
     }
 
     pub fn find_transaction_by_id(id: &str, conn: &DbConn) -> Option<Transactions> {
@@ -105,10 +106,8 @@ impl Account {
             .expect("Error saving new account")
     }
 
-    pub fn remove_account(id: String, conn: &DbConn) -> () {
-        diesel::delete(account::table.filter(account::id.eq(id)))
-            .execute(&**conn)
-            .expect("Error deleting account");
+    pub fn remove_account(id: String, conn: &DbConn) -> QueryResult<usize> {
+        diesel::delete(account::table.filter(account::id.eq(id))).execute(&**conn)
     }
 
     pub fn get_account(username: String, password: String, conn: &DbConn) -> Account {
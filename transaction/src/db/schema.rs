@@ -2,14 +2,15 @@ table! {
       transactions (id) {
         id -> Text,
         account_no -> Text,
-        amount -> Double,
-        new_balance -> Double,
+        amount -> Numeric,
+        new_balance -> Numeric,
         account_type -> Text,
         changed_by -> Text,
         from_to -> Text,
         direction -> Text,
         description -> Text,
         created_at -> Timestamp,
+        correlation_id -> Nullable<Text>,
     }
 }
 
@@ -22,4 +23,14 @@ table! {
   }
 }
 
-allow_tables_to_appear_in_same_query!(transactions, account);
+table! {
+    inbox (message_id) {
+        message_id -> Text,
+        topic -> Text,
+        partition -> Int4,
+        message_offset -> Int8,
+        processed_at -> Timestamp,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(transactions, account, inbox);
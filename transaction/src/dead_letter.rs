@@ -0,0 +1,82 @@
+use crate::backend::{Backend, Message, ProducerBackend};
+use crate::kafka_consumer::{INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::shutdown::Shutdown;
+use chrono::{NaiveDateTime, Utc};
+use log::{info, warn};
+use std::thread;
+
+/// Envelope for a message that could not be decoded or processed, published as json to `<topic>.dlq` with the key
+/// of the original message. Key and value are base64 encoded, so they can be replayed byte for byte.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: String,
+    pub value: String,
+    pub error: String,
+    pub failed_at: NaiveDateTime
+}
+
+impl DeadLetter {
+    pub fn new(topic: &str, partition: i32, message: &Message, error: String) -> Self {
+        DeadLetter {
+            topic: topic.to_string(),
+            partition,
+            offset: message.offset,
+            key: base64::encode(&message.key),
+            value: base64::encode(&message.value),
+            error,
+            failed_at: Utc::now().naive_utc()
+        }
+    }
+}
+
+pub fn dlq_topic(topic: &str) -> String {
+    format!("{}.dlq", topic)
+}
+
+pub struct DeadLetterProducer {
+    producer: Box<dyn ProducerBackend>
+}
+
+impl DeadLetterProducer {
+    pub fn new(producer: Box<dyn ProducerBackend>) -> DeadLetterProducer {
+        DeadLetterProducer {
+            producer
+        }
+    }
+
+    /// Keeps retrying with backoff until the dead letter is stored, so the original offset is never committed without
+    /// it. Only gives up when the service shuts down.
+    pub fn send(&mut self, dead_letter: &DeadLetter, key: &[u8], shutdown: &Shutdown) -> Result<(), String> {
+        let topic = dlq_topic(&dead_letter.topic);
+        let value = match serde_json::to_string(dead_letter) {
+            Ok(v) => v,
+            Err(e) => panic!("Error serializing dead letter: {}", e)
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match self.producer.send(&topic, key, value.as_bytes()) {
+                Ok(_delivery) => {
+                    info!("Stored {}:{}@{} on {}", dead_letter.topic, dead_letter.partition, dead_letter.offset, topic);
+                    return Ok(());
+                }
+                Err(e) if !shutdown.is_triggered() => {
+                    warn!("Retrying in {:?} storing dead letter on {} because of {}", backoff, topic, e);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+pub fn get_dead_letter_producer(backend: &Backend) -> DeadLetterProducer {
+    let producer = match backend.producer() {
+        Ok(p) => p,
+        Err(e) => panic!("Error creating dead letter producer: {}", e)
+    };
+    DeadLetterProducer::new(producer)
+}
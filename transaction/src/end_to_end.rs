@@ -0,0 +1,159 @@
+//! Tests of the service as `main` runs it, from the events on the memory broker through the handlers to the database.
+//! They need the database of `DATABASE_URL_TRANSACTION`, so they're ignored by default, run them with
+//! `cargo test -- --ignored`.
+
+use crate::backend::ProducerBackend;
+use crate::config::{BackendKind, KafkaConfig};
+use crate::db::models::{Account, Transactions};
+use crate::db::schema::transactions;
+use crate::db::util::{get_id, new_account};
+use crate::db::{self, DbConn, Pool};
+use crate::embedded_registry::serve_for_tests;
+use crate::events::{AccountCreationFailed, AvroRecord, BalanceChanged, BalanceChangedKey};
+use crate::kafka_consumer::{consume, CommitPolicy};
+use crate::kafka_producer::Key;
+use crate::memory_broker::testing::{dead_letters, eventually, produce};
+use crate::memory_broker::MemoryBroker;
+use crate::money::Money;
+use crate::shutdown::Shutdown;
+use crate::supervisor::ConsumerMonitor;
+use crate::{handlers, migrations};
+use diesel::prelude::*;
+use lazy_static::lazy_static;
+use std::env;
+
+lazy_static! {
+    static ref POOL: Pool = start();
+}
+
+/// Starts the consumer once for all tests, they run until the test process ends.
+fn start() -> Pool {
+    serve_for_tests();
+    let database_url = env::var("DATABASE_URL_TRANSACTION").expect("DATABASE_URL_TRANSACTION must be set");
+    let pool = db::init_pool(&database_url);
+    migrations::run_migrations(pool.clone());
+    let mut config = KafkaConfig::default();
+    config.backend = BackendKind::Memory;
+    config.consumer.group = String::from("transaction-end-to-end");
+    config.consumer.workers = 2;
+    let consumer_pool = pool.clone();
+    let policy = CommitPolicy::from_config(&config.consumer);
+    let monitor = ConsumerMonitor::new(&config.consumer.group);
+    consume(&config, move || handlers(&consumer_pool), policy, Shutdown::default(), monitor);
+    pool
+}
+
+fn conn() -> DbConn {
+    DbConn(POOL.get().expect("Connection should be available"))
+}
+
+fn money(s: &str) -> Money {
+    s.parse().expect("Amount should parse")
+}
+
+fn balance_changed(id: &str, account_no: &str, changed_by: &str, new_balance: &str) {
+    let key = BalanceChangedKey {
+        bank_code: String::from("OPEN"),
+        account_no: account_no.to_string()
+    };
+    let record = BalanceChanged {
+        id: id.to_string(),
+        account_no: account_no.to_string(),
+        new_balance: money(new_balance),
+        changed_by: money(changed_by),
+        from_to: String::from("cash"),
+        description: String::from("end-to-end"),
+        correlation_id: Some(format!("correlation-{}", id)),
+        causation_id: None
+    };
+    produce(
+        Key::Avro {
+            record_name: BalanceChangedKey::NAME,
+            values: key.to_values()
+        },
+        &record
+    );
+}
+
+fn transactions_of(account_no: &str) -> Vec<Transactions> {
+    transactions::table
+        .filter(transactions::account_no.eq(account_no))
+        .order(transactions::created_at)
+        .load::<Transactions>(&*conn())
+        .expect("Transactions should load")
+}
+
+#[test]
+#[ignore]
+fn balance_change_is_added_to_the_transactions_of_the_account() {
+    lazy_static::initialize(&POOL);
+    let account_no = new_account();
+    let id = get_id();
+    balance_changed(&id, &account_no, "-10.50", "89.50");
+
+    let tx = eventually("the transaction", || transactions_of(&account_no).into_iter().next());
+    assert_eq!(tx.changed_by, id);
+    assert_eq!(tx.amount, money("-10.50"));
+    assert_eq!(tx.new_balance, money("89.50"));
+    assert_eq!(tx.direction, "DEBIT");
+    assert_eq!(tx.from_to, "cash");
+    assert_eq!(tx.correlation_id, Some(format!("correlation-{}", id)));
+}
+
+#[test]
+#[ignore]
+fn republished_balance_change_is_added_once() {
+    lazy_static::initialize(&POOL);
+    let account_no = new_account();
+    let id = get_id();
+    // The relay of account can publish a change again, at another offset. Changes of an account share a key, so the
+    // next one is handled after both.
+    balance_changed(&id, &account_no, "10.00", "10.00");
+    balance_changed(&id, &account_no, "10.00", "10.00");
+    let next = get_id();
+    balance_changed(&next, &account_no, "5.00", "15.00");
+
+    eventually("the next transaction", || {
+        transactions_of(&account_no).into_iter().find(|tx| tx.changed_by == next)
+    });
+    let changes: Vec<String> = transactions_of(&account_no).into_iter().map(|tx| tx.changed_by).collect();
+    assert_eq!(changes, vec![id, next]);
+}
+
+#[test]
+#[ignore]
+fn account_is_removed_when_its_creation_failed_without_a_reason() {
+    lazy_static::initialize(&POOL);
+    let username = get_id();
+    let account = Account::get_account(username.clone(), String::from("password"), &conn());
+    let failed = AccountCreationFailed {
+        id: account.id.clone(),
+        reason: String::new(),
+        correlation_id: None,
+        causation_id: None
+    };
+    produce(Key::Raw(account.id.clone()), &failed);
+
+    eventually("the account to be removed", || {
+        if Account::find_account_by_username(&username, &conn()).is_none() {
+            Some(())
+        } else {
+            None
+        }
+    });
+}
+
+#[test]
+#[ignore]
+fn event_that_is_not_avro_becomes_a_dead_letter() {
+    lazy_static::initialize(&POOL);
+    let key = get_id();
+    let mut producer = MemoryBroker::global().producer();
+    let delivery = producer.send(BalanceChanged::TOPIC, key.as_bytes(), b"not avro").expect("Send should succeed");
+
+    let dead_letter = eventually("the dead letter", || {
+        dead_letters(BalanceChanged::TOPIC).into_iter().find(|d| d.key == base64::encode(&key))
+    });
+    assert_eq!((dead_letter.partition, dead_letter.offset), (delivery.partition, delivery.offset));
+    assert_eq!(dead_letter.value, base64::encode(b"not avro"));
+}
@@ -0,0 +1,64 @@
+use crate::kafka_consumer::ProcessError;
+use crate::money::Money;
+use avro_rs::types::Value;
+use serde::de::DeserializeOwned;
+
+/// Value of a record on `TOPIC`, implemented by the structs `build.rs` generates from `res/*.avsc`. Fields are decoded
+/// from the Avro record by name, so the order of the fields in the schema doesn't matter, and a missing field or a field
+/// of the wrong type gives a permanent `ProcessError`.
+pub trait AvroRecord: DeserializeOwned {
+    const TOPIC: &'static str;
+    /// Full name of the record, including the namespace, as used by the record name strategies.
+    const NAME: &'static str;
+    /// Whether this is the record of the key instead of the value.
+    const IS_KEY: bool;
+    /// Content of the `.avsc` file the struct was generated from.
+    const SCHEMA: &'static str;
+    /// Fields holding a decimal, which are `Money` in the struct.
+    const DECIMAL_FIELDS: &'static [&'static str];
+
+    /// Fields in the order of the schema, as needed by `AvroEncoder::encode`.
+    fn to_values(&self) -> Vec<(&'static str, Value)>;
+
+    /// The bytes of a decimal field are turned into the string of the amount first, since serde has no way to tell
+    /// them apart from other bytes. A field that is still a double, in a record produced before, is read as is.
+    fn from_values(values: &[(String, Value)]) -> Result<Self, ProcessError> {
+        let decoding_error = |e: String| ProcessError::Permanent(format!("Error decoding {} record: {}", Self::TOPIC, e));
+        let values = values
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Bytes(bytes) if Self::DECIMAL_FIELDS.contains(&name.as_str()) => decimal_value(bytes)?,
+                    Value::Union(inner) if Self::DECIMAL_FIELDS.contains(&name.as_str()) => match inner.as_ref() {
+                        Value::Bytes(bytes) => Value::Union(Box::new(decimal_value(bytes)?)),
+                        _ => value.clone()
+                    },
+                    _ => value.clone()
+                };
+                Ok((name.clone(), value))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(decoding_error)?;
+        avro_rs::from_value(&Value::Record(values)).map_err(|e| decoding_error(e.to_string()))
+    }
+}
+
+fn decimal_value(bytes: &[u8]) -> Result<Value, String> {
+    Money::from_avro_bytes(bytes).map(|money| Value::String(money.to_string()))
+}
+
+/// A schema from the `res` directory of one of the services.
+pub struct SchemaFile {
+    pub topic: &'static str,
+    pub name: &'static str,
+    pub schema: &'static str,
+    pub is_key: bool
+}
+
+fn json_values<R: AvroRecord>(json: &str) -> Result<(&'static str, Vec<(&'static str, Value)>), String> {
+    serde_json::from_str::<R>(json)
+        .map(|r| (R::NAME, r.to_values()))
+        .map_err(|e| format!("Error decoding {} from json: {}", R::NAME, e))
+}
+
+include!(concat!(env!("OUT_DIR"), "/events.rs"));
@@ -0,0 +1,53 @@
+use crate::db::schema::inbox;
+use crate::db::DbConn;
+use crate::kafka_consumer::{MessageContext, ProcessError};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{self, prelude::*};
+
+/// How a message is recognized as already processed.
+pub enum InboxKey<'a> {
+    /// Topic, partition and offset, catching a redelivery of the same message, like after a crash before the offset
+    /// was committed. A record that is produced again, like the outbox relay does after a crash, gets another offset,
+    /// so use the id of the event for records that have one.
+    #[allow(dead_code)]
+    Position,
+    /// Id of the event, unique within the topic. Also catches the same event being produced more than once.
+    EventId(&'a str)
+}
+
+#[derive(Insertable)]
+#[table_name = "inbox"]
+struct InboxEntry {
+    message_id: String,
+    topic: String,
+    partition: i32,
+    message_offset: i64,
+    processed_at: NaiveDateTime
+}
+
+/// Runs `handler` in one database transaction with recording the message in the inbox, so the writes of `handler` are
+/// done once per message, using `conn` for those writes is required. When the message is already in the inbox an
+/// ignorable error is returned without running `handler`, when `handler` fails the message is not recorded.
+pub fn process_once<F>(ctx: &MessageContext, key: InboxKey, conn: &DbConn, handler: F) -> Result<(), ProcessError>
+where
+    F: FnOnce() -> Result<(), ProcessError>
+{
+    let message_id = match key {
+        InboxKey::Position => ctx.position(),
+        InboxKey::EventId(id) => format!("{}/{}", ctx.topic, id)
+    };
+    conn.transaction(|| {
+        let entry = InboxEntry {
+            message_id: message_id.clone(),
+            topic: ctx.topic.clone(),
+            partition: ctx.partition,
+            message_offset: ctx.offset,
+            processed_at: Utc::now().naive_utc()
+        };
+        let inserted = diesel::insert_into(inbox::table).values(&entry).on_conflict_do_nothing().execute(&**conn)?;
+        if inserted == 0 {
+            return Err(ProcessError::Ignorable(format!("{} was already processed", message_id)));
+        }
+        handler()
+    })
+}
@@ -16,171 +16,189 @@ extern crate serde_json;
 use dotenv::dotenv;
 use std::env;
 
+mod backend;
+mod command;
+mod config;
+mod correlation;
 mod db;
+mod dead_letter;
+mod embedded_registry;
+#[cfg(test)]
+mod end_to_end;
+mod events;
+mod inbox;
 mod kafka_consumer;
 mod kafka_producer;
 mod logger;
-
+mod memory_broker;
+mod money;
+mod producer_queue;
+mod schema_registry;
+mod shutdown;
+mod supervisor;
+
+use crate::command::Command;
+use crate::config::KafkaConfig;
+use crate::correlation::{RequestId, RequestIds};
 use crate::db::models::{Account, Transactions};
 
 use crate::db::Pool;
-use crate::kafka_consumer::{consume, ValuesProcessor};
-use crate::kafka_producer::get_producer;
+use crate::events::{AccountCreationConfirmed,
+                    AccountCreationFailed,
+                    AvroRecord,
+                    BalanceChanged,
+                    BalanceChangedKey,
+                    ConfirmAccountCreation,
+                    ConfirmMoneyTransfer,
+                    MoneyTransferConfirmed,
+                    MoneyTransferFailed,
+                    OWNED_SCHEMAS};
+use crate::inbox::{process_once, InboxKey};
+use crate::kafka_consumer::{consume, CommitPolicy, Handlers, MessageContext, ProcessError, ValuesProcessor};
 use crate::logger::setup_logger;
+use crate::money::Money;
+use crate::producer_queue::{ProducerData, ProducerSender, ProducerStats};
+use crate::schema_registry::register_schemas;
+use crate::shutdown::{Accepting, Shutdown};
+use crate::supervisor::ConsumerMonitor;
 use avro_rs::types::Value;
 use db::DbConn;
-use diesel::pg::PgConnection;
-use log::{error, info};
+use log::{error, info, warn};
 use rocket::config::{Config, Environment, LoggingLevel};
+use rocket::http::{Header, Status};
+use rocket::response::status::Custom;
 use rocket_contrib::json::Json;
-use schema_registry_converter::schema_registry::SubjectNameStrategy;
-use std::collections::HashMap;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::thread;
-
-struct ProducerData {
-    topic: &'static str,
-    key: String,
-    values: Vec<(&'static str, Value)>
-}
-
+use std::time::Duration;
+use std::{process, thread};
+
+/// Seconds a client is asked to wait before trying again, when the producer queue is full.
+const RETRY_AFTER_SECONDS: u32 = 1;
+/// Longest an HTTP handler waits for Kafka to acknowledge its record, well below the timeouts of clients, so slow
+/// acknowledgements don't hold on to the Rocket workers.
+const ACK_WAIT: Duration = Duration::from_millis(500);
+/// Longest the requests in flight are waited for before exiting.
+const REQUEST_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
 struct AccContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for AccContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_acc(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let acc_event = AccountCreationConfirmed::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = acc_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_acc(acc_event))
     }
 }
 
-fn handle_acc(values: &[(String, Value)], conn: &PgConnection, sender: &SyncSender<ProducerData>) {
-    let key = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
-    };
-
-    let account_no = match &values[1] {
-        (_account_no, Value::String(ref v)) => ("account_no", Value::String(v.clone())),
-        _ => panic!("Not an account_no, while that was expected")
-    };
-
-    let token = match &values[2] {
-        (_token, Value::String(ref v)) => ("token", Value::String(v.clone())),
-        _ => panic!("Not token, while that was expected")
-    };
-
-    let reason = match &values[3] {
-        (_reason, Value::String(ref v)) => ("reason", Value::String(v.clone())),
-        _ => panic!("Not reason, while that was expected")
-    };
-
-    /*
-    let producer_data = ProducerData {
-        topic: "confirm_money_transfer",
-        key,
-        values: vec![account_no, token, reason]
-    };
-
-    sender.send(producer_data).unwrap();
-    */
+/// Nothing to store yet, the account was stored by the login that requested its creation.
+fn handle_acc(_acc_event: AccountCreationConfirmed) -> Result<(), ProcessError> {
+    Ok(())
 }
 
+#[derive(Clone)]
 struct AcfContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for AcfContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_acf(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let acf_event = AccountCreationFailed::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = acf_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_acf(acf_event, &conn))
     }
 }
 
-fn handle_acf(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
-    };
-
-    let reason = match &values[1] {
-        (_reason, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an reason, while that was expected")
-    };
-
-    if reason.is_empty() {
-        db::Account::remove_account(id.clone(), conn);
+fn handle_acf(acf_event: AccountCreationFailed, conn: &DbConn) -> Result<(), ProcessError> {
+    if acf_event.reason.is_empty() {
+        db::Account::remove_account(acf_event.id, conn)?;
     }
+    Ok(())
 }
 
+#[derive(Clone)]
 struct MtcContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for MtcContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_mtc(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let mtc_event = MoneyTransferConfirmed::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = mtc_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_mtc(mtc_event))
     }
 }
 
-fn handle_mtc(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
-    };
-    // sender.send(producer_data).unwrap();
+/// Nothing to store yet, the transfer shows up in the transactions through the balance changes.
+fn handle_mtc(_mtc_event: MoneyTransferConfirmed) -> Result<(), ProcessError> {
+    Ok(())
 }
 
+#[derive(Clone)]
 struct MtfContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for MtfContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_mtf(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let mtf_event = MoneyTransferFailed::from_values(values)?;
+        let conn = DbConn(self.pool.get()?);
+        let id = mtf_event.id.clone();
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_mtf(mtf_event))
     }
 }
 
-fn handle_mtf(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
-    };
-    // sender.send(producer_data).unwrap();
+/// Nothing to store yet, a failed transfer changes no balances.
+fn handle_mtf(_mtf_event: MoneyTransferFailed) -> Result<(), ProcessError> {
+    Ok(())
 }
 
+#[derive(Clone)]
 struct BcContext {
-    sender: SyncSender<ProducerData>,
     pool: Pool
 }
 
 impl ValuesProcessor for BcContext {
-    fn process(&mut self, values: &[(String, Value)]) {
-        handle_bc(values, &DbConn(self.pool.get().unwrap()), &self.sender)
+    fn process(&mut self, ctx: &MessageContext, values: &[(String, Value)]) -> Result<(), ProcessError> {
+        let bc_event = BalanceChanged::from_values(values)?;
+        let bc_key = ctx.key.record::<BalanceChangedKey>()?;
+        let conn = DbConn(self.pool.get()?);
+        // The id is the one of the transfer, shared by the balance changes of both accounts. The outbox relay can
+        // publish a change again, at another offset, so the position of the message doesn't catch that.
+        let id = format!("{}/{}", bc_event.id, bc_key.account_no);
+        process_once(ctx, InboxKey::EventId(&id), &conn, || handle_bc(ctx, bc_event, bc_key, &conn))
     }
 }
 
-fn handle_bc(values: &[(String, Value)], conn: &DbConn, sender: &SyncSender<ProducerData>) {
-    let id = match &values[0] {
-        (_id, Value::String(ref v)) => v.clone(),
-        _ => panic!("Not an id, while that was expected")
+/// Adds the change to the transactions of the account, the account is taken from the key so it can be used for
+/// routing without decoding the value.
+fn handle_bc(ctx: &MessageContext, bc_event: BalanceChanged, bc_key: BalanceChangedKey, conn: &DbConn) -> Result<(), ProcessError> {
+    let direction = if bc_event.changed_by < Money::ZERO { "DEBIT" } else { "CREDIT" };
+    let tx = Transactions {
+        amount: bc_event.changed_by,
+        new_balance: bc_event.new_balance,
+        changed_by: bc_event.id,
+        from_to: bc_event.from_to,
+        direction: String::from(direction),
+        description: bc_event.description,
+        created_at: ctx.received_at,
+        correlation_id: ctx.correlation_id.clone(),
+        ..Transactions::new(bc_key.account_no)
     };
-
-    // Transactions::insert_transaction()
-
-    // sender.send(producer_data).unwrap();
+    Transactions::insert_transaction(tx, conn)?;
+    Ok(())
 }
 
 // https://github.com/SergioBenitez/Rocket/issues/714
 use rocket::State;
 use std::ops::Deref;
-struct JobSender(SyncSender<ProducerData>);
+struct JobSender(ProducerSender);
 impl Deref for JobSender {
-    type Target = mpsc::SyncSender<ProducerData>;
+    type Target = ProducerSender;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -193,24 +211,66 @@ struct LoginData {
     password: String
 }
 
+/// Answer of the HTTP handlers when their record could not be produced.
+#[derive(Debug, Responder)]
+enum ProduceError {
+    /// The record could not be queued, the client can try again after the `Retry-After` header.
+    #[response(status = 503)]
+    Unavailable(String, Header<'static>),
+    /// The record was not acknowledged by Kafka.
+    #[response(status = 502)]
+    NotAcknowledged(String)
+}
+
+/// Queues the record without blocking the Rocket worker and waits at most `ACK_WAIT` for Kafka to acknowledge it. The
+/// status is `200 OK` when it was acknowledged, and `202 Accepted` when it's still being send.
+fn produce(sender: &ProducerSender, producer_data: ProducerData) -> Result<Status, ProduceError> {
+    let receipt = match sender.try_send(producer_data) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Refusing request because {}", e);
+            return Err(ProduceError::Unavailable(
+                e.to_string(),
+                Header::new("Retry-After", RETRY_AFTER_SECONDS.to_string())
+            ));
+        }
+    };
+    match receipt.wait_at_most(ACK_WAIT) {
+        Ok(Some(_)) => Ok(Status::Ok),
+        Ok(None) => {
+            info!("Record of request not acknowledged within {:?}, answering it as accepted", ACK_WAIT);
+            Ok(Status::Accepted)
+        }
+        Err(e) => {
+            error!("Record of request not acknowledged: {}", e);
+            Err(ProduceError::NotAcknowledged(e))
+        }
+    }
+}
+
 #[post("/login", data = "<data>")]
-fn login(data: Json<LoginData>, conn: DbConn, sender: State<JobSender>) -> Json<Account> {
+fn login(
+    _accepting: Accepting,
+    request_id: RequestId,
+    data: Json<LoginData>,
+    conn: DbConn,
+    sender: State<JobSender>
+) -> Result<Custom<Json<Account>>, ProduceError> {
     let data: LoginData = data.into_inner();
     let acc: Account = db::Account::get_account(data.username, data.password, &conn);
     let key = acc.id.clone();
+    let trace = request_id.trace();
 
-    let id = ("id", Value::String(key.clone()));
-    let _type = ("_type", Value::String(String::from("MANUAL")));
-
-    let producer_data = ProducerData {
-        topic: "confirm_account_creation",
-        key,
-        values: vec![id, _type]
-    };
+    let producer_data = ProducerData::new(key.clone(), &ConfirmAccountCreation {
+        id: key,
+        _type: String::from("MANUAL"),
+        correlation_id: Some(trace.correlation_id),
+        causation_id: Some(trace.causation_id)
+    });
 
     // fetch_messages();
-    sender.try_send(producer_data).unwrap();
-    Json(acc)
+    let status = produce(&sender, producer_data)?;
+    Ok(Custom(status, Json(acc)))
 }
 
 #[derive(Deserialize, Serialize)]
@@ -218,31 +278,42 @@ fn login(data: Json<LoginData>, conn: DbConn, sender: State<JobSender>) -> Json<
 struct MoneyTransfer {
     id: String,
     token: String,
-    amount: f64,
+    amount: Money,
     from: String,
     to: String,
     description: String
 }
 #[post("/tx", data = "<data>")]
-fn transact(data: Json<MoneyTransfer>, conn: DbConn, sender: State<JobSender>) -> Json<MoneyTransfer> {
+fn transact(
+    _accepting: Accepting,
+    request_id: RequestId,
+    data: Json<MoneyTransfer>,
+    conn: DbConn,
+    sender: State<JobSender>
+) -> Result<Custom<Json<MoneyTransfer>>, ProduceError> {
     let data: MoneyTransfer = data.into_inner();
     let key = data.id.clone();
+    let trace = request_id.trace();
+
+    let producer_data = ProducerData::new(key, &ConfirmMoneyTransfer {
+        id: data.id.clone(),
+        token: data.token.clone(),
+        amount: data.amount,
+        from: data.from.clone(),
+        to: data.to.clone(),
+        description: data.description.clone(),
+        correlation_id: Some(trace.correlation_id),
+        causation_id: Some(trace.causation_id)
+    });
+
+    let status = produce(&sender, producer_data)?;
+    Ok(Custom(status, Json(data)))
+}
 
-    let id = ("id", Value::String(key.clone()));
-    let token = ("token", Value::String(data.token.clone()));
-    let amount = ("amount", Value::Double(data.amount.clone()));
-    let from = ("from", Value::String(data.from.clone()));
-    let to = ("to", Value::String(data.to.clone()));
-    let description = ("description", Value::String(data.description.clone()));
-
-    let producer_data = ProducerData {
-        topic: "confirm_money_transfer",
-        key,
-        values: vec![id, token, amount, from, to, description]
-    };
-
-    sender.try_send(producer_data).unwrap();
-    Json(data)
+/// Throughput and latency of the producer since the service started.
+#[get("/producer")]
+fn producer_stats(sender: State<JobSender>) -> Json<ProducerStats> {
+    Json(sender.stats())
 }
 
 embed_migrations!("./migrations");
@@ -256,8 +327,7 @@ mod migrations {
     }
 }
 
-fn launch_rocket(tx: &SyncSender<ProducerData>, p: &Pool) {
-    migrations::run_migrations(p.clone());
+fn launch_rocket(tx: &ProducerSender, p: &Pool, monitor: ConsumerMonitor, shutdown: Shutdown) {
     let config = Config::build(Environment::Development)
         .address("127.0.0.1")
         .port(8071)
@@ -265,15 +335,26 @@ fn launch_rocket(tx: &SyncSender<ProducerData>, p: &Pool) {
         .log_level(LoggingLevel::Normal)
         .unwrap();
     let rocket = rocket::custom(config);
-    let rocket = rocket.mount("/v1", routes![login, transact]);
+    let rocket = rocket.mount("/v1", routes![
+        login,
+        transact,
+        producer_stats,
+        supervisor::status,
+        supervisor::pause,
+        supervisor::resume
+    ]);
     log::set_max_level(log::LevelFilter::max());
-    let rocket = rocket.manage(p.clone()).manage(JobSender(tx.clone()));
+    let rocket = rocket
+        .manage(p.clone())
+        .manage(JobSender(tx.clone()))
+        .manage(monitor)
+        .manage(shutdown)
+        .attach(RequestIds);
     error!("Launch error {:#?}", rocket.launch());
 }
 
 fn fetch_messages() {
     use kafka::client::{FetchPartition, KafkaClient};
-    use log::warn;
     use schema_registry_converter::Decoder;
 
     let mut client = KafkaClient::new(vec!["127.0.0.1:9092".to_owned()]);
@@ -327,102 +408,86 @@ fn fetch_messages() {
 }
 
 fn handle_cmt(values: &[(String, Value)]) {
-    let id = match &values[0] {
-        (_id, Value::String(v)) => v.clone(),
-        _ => panic!("Not a an id, while that was expected")
-    };
-
-    let token = match &values[1] {
-        (_token, Value::String(v)) => v.clone(),
-        _ => panic!("Not a an token, while that was expected")
-    };
-
-    let amount = match &values[2] {
-        (ref _amount, Value::Double(ref v)) => v,
-        _ => panic!("Not a an amount, while that was expected")
-    };
-
-    let from = match &values[3] {
-        (_from, Value::String(v)) => v.clone(),
-        _ => panic!("Not a an from, while that was expected")
-    };
-
-    println!("id: {} / token: {} / amount: {} / from: {}", id, token, amount, from);
+    match ConfirmMoneyTransfer::from_values(values) {
+        Ok(v) => println!("id: {} / token: {} / amount: {} / from: {}", v.id, v.token, v.amount, v.from),
+        Err(e) => warn!("{}", e)
+    }
 }
 
-fn main() {
-    setup_logger(None);
-
-    let group_id = "transaction";
-    let (tx, rx) = mpsc::sync_channel(1);
-    thread::spawn(move || send_loop(&rx));
-
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL_TRANSACTION").expect("DATABASE_URL_TRANSACTION must be set");
-    let pool = db::init_pool(&database_url);
-
-    let acc_handle = consume(
-        group_id,
-        "account_creation_confirmed",
-        Box::from(AccContext {
-            sender: tx.clone(),
+/// Handlers of the consumed events, storing their results in the database of `pool`.
+fn handlers(pool: &Pool) -> Handlers {
+    Handlers::new()
+        .register::<AccountCreationConfirmed, _>(AccContext {
             pool: pool.clone()
         })
-    );
-    let acf_handle = consume(
-        group_id,
-        "account_creation_failed",
-        Box::from(AcfContext {
-            sender: tx.clone(),
+        .register::<AccountCreationFailed, _>(AcfContext {
             pool: pool.clone()
         })
-    );
-    let mtc_handle = consume(
-        group_id,
-        "money_transfer_confirmed",
-        Box::from(MtcContext {
-            sender: tx.clone(),
+        .register::<MoneyTransferConfirmed, _>(MtcContext {
             pool: pool.clone()
         })
-    );
-    let mtf_handle = consume(
-        group_id,
-        "money_transfer_failed",
-        Box::from(MtfContext {
-            sender: tx.clone(),
+        .register::<MoneyTransferFailed, _>(MtfContext {
             pool: pool.clone()
         })
-    );
-    let bc_handle = consume(
-        group_id,
-        "balance_changed",
-        Box::from(BcContext {
-            sender: tx.clone(),
+        .register::<BalanceChanged, _>(BcContext {
             pool: pool.clone()
         })
-    );
-
-    let api_handle = thread::spawn(move || launch_rocket(&tx.clone(), &pool.clone()));
-
-    acc_handle.join().expect_err("Error closing acc handler");
-    acf_handle.join().expect_err("Error closing acf handler");
-    mtc_handle.join().expect_err("Error closing mtc handler");
-    mtf_handle.join().expect_err("Error closing mtf handler");
-    bc_handle.join().expect_err("Error closing bc handler");
-    api_handle.join().expect_err("Error closing api handler");
 }
 
-fn send_loop(receiver: &Receiver<ProducerData>) {
-    let mut producer = get_producer();
-    let mut cache = HashMap::new();
-    loop {
-        let producer_data = match receiver.recv() {
-            Ok(v) => v,
-            Err(e) => panic!("Error reading future from receiver: {}", e)
-        };
-        let strategy = cache
-            .entry(producer_data.topic)
-            .or_insert_with(|| SubjectNameStrategy::TopicNameStrategy(producer_data.topic.into(), false));
-        producer.send(producer_data.topic, producer_data.key, producer_data.values, strategy);
+fn main() {
+    setup_logger(None);
+
+    dotenv().ok();
+    let kafka_config = KafkaConfig::load("transaction");
+    match Command::parse(&env::args().skip(1).collect::<Vec<_>>(), &kafka_config) {
+        Ok(None) => (),
+        Ok(Some(command)) => {
+            let result = command::run(command, &kafka_config, |database_url| {
+                let pool = db::init_pool(&database_url.to_string());
+                migrations::run_migrations(pool.clone());
+                handlers(&pool)
+            });
+            match result {
+                Ok(()) => process::exit(0),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1)
+                }
+            }
+        }
+        Err(e) => {
+            error!("{}\n{}", e, command::USAGE);
+            process::exit(2)
+        }
     }
+    if let Err(e) = embedded_registry::start_from_env().and_then(|()| register_schemas(OWNED_SCHEMAS)) {
+        panic!("Refusing to start: {}", e)
+    }
+    let shutdown = Shutdown::listen();
+    let producer_stop = Shutdown::default();
+    let (tx, producer_handle) = producer_queue::start(&kafka_config, producer_stop.clone());
+
+    let database_url = env::var("DATABASE_URL_TRANSACTION").expect("DATABASE_URL_TRANSACTION must be set");
+    let pool = db::init_pool(&database_url);
+    // Before anything uses the database, so the consumer and the API never see an old schema.
+    migrations::run_migrations(pool.clone());
+
+    let monitor = ConsumerMonitor::new(&kafka_config.consumer.group);
+    let consumer_pool = pool.clone();
+    let policy = CommitPolicy::from_config(&kafka_config.consumer);
+    let consumer_handle = consume(&kafka_config, move || handlers(&consumer_pool), policy, shutdown.clone(), monitor.clone());
+
+    let api_shutdown = shutdown.clone();
+    thread::spawn(move || launch_rocket(&tx, &pool, monitor, api_shutdown));
+
+    consumer_handle.join().expect("Error closing consumer");
+    // The consumer also stops when it fails, so make sure no new requests are accepted before waiting for them. The
+    // producer stops afterwards, as the requests still queue records.
+    shutdown.trigger();
+    shutdown.wait_for_requests(REQUEST_DRAIN_TIMEOUT);
+    producer_stop.trigger();
+    producer_handle.join().expect("Error closing producer");
+    // Rocket 0.4 can't be stopped once launched, exiting the process takes it down.
+    info!("Shutdown complete");
+    process::exit(0);
 }
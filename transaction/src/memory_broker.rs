@@ -0,0 +1,277 @@
+use crate::backend::{ConsumerBackend, Message, MessageSet, OffsetReset, ProducerBackend};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+const POLL_WAIT: Duration = Duration::from_millis(100);
+const MAX_POLL_MESSAGES: usize = 500;
+
+lazy_static! {
+    static ref GLOBAL: MemoryBroker = MemoryBroker::new(match env::var("MEMORY_BROKER_PARTITIONS") {
+        Ok(val) => val.parse().expect("MEMORY_BROKER_PARTITIONS must be a number"),
+        Err(_e) => 1
+    });
+}
+
+#[derive(Default)]
+struct State {
+    /// Key and value of the messages per topic and partition, the offset is the index.
+    topics: HashMap<String, Vec<Vec<(Vec<u8>, Vec<u8>)>>>,
+    /// Offset of the next message to consume, per group, topic and partition.
+    committed: HashMap<(String, String, i32), i64>
+}
+
+impl State {
+    fn partitions(&mut self, topic: &str, partitions: i32) -> &mut Vec<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.topics.entry(topic.to_string()).or_insert_with(|| vec![Vec::new(); partitions as usize])
+    }
+}
+
+/// Broker keeping all topics in memory, partitioned like Kafka does, so messages with the same key end up on the same
+/// partition in the order they were send. Topics are created on first use. A consumer gets all partitions of its
+/// topics, as the services run one consumer per group.
+#[derive(Clone)]
+pub struct MemoryBroker {
+    state: Arc<(Mutex<State>, Condvar)>,
+    partitions: i32
+}
+
+impl MemoryBroker {
+    pub fn new(partitions: i32) -> MemoryBroker {
+        MemoryBroker {
+            state: Arc::new((Mutex::new(State::default()), Condvar::new())),
+            partitions: partitions.max(1)
+        }
+    }
+
+    /// Broker shared by the whole process, with `MEMORY_BROKER_PARTITIONS` partitions per topic, 1 by default.
+    pub fn global() -> MemoryBroker {
+        GLOBAL.clone()
+    }
+
+    pub fn consumer(&self, group: &str, topics: &[String]) -> MemoryConsumer {
+        MemoryConsumer {
+            broker: self.clone(),
+            group: group.to_string(),
+            topics: topics.to_vec(),
+            positions: HashMap::new(),
+            consumed: HashMap::new(),
+            poll_wait: POLL_WAIT
+        }
+    }
+
+    pub fn producer(&self) -> MemoryProducer {
+        MemoryProducer {
+            broker: self.clone()
+        }
+    }
+
+    /// All messages on a partition of the topic, to check what was produced.
+    pub fn messages(&self, topic: &str, partition: i32) -> Vec<Message> {
+        let mut state = self.lock();
+        let partitions = state.partitions(topic, self.partitions);
+        partitions.get(partition as usize).map_or_else(Vec::new, |messages| to_messages(messages, 0))
+    }
+
+    /// Offset of the next message `group` will consume from the partition, if it committed any.
+    pub fn committed_offset(&self, group: &str, topic: &str, partition: i32) -> Option<i64> {
+        self.lock().committed.get(&(group.to_string(), topic.to_string(), partition)).cloned()
+    }
+
+    /// Sets the offsets `group` continues the partitions of `topic` from, returning them per partition.
+    pub fn reset_offsets(&self, group: &str, topic: &str, to: OffsetReset) -> Result<Vec<(i32, i64)>, String> {
+        let mut state = self.lock();
+        let lengths: Vec<i64> = state.partitions(topic, self.partitions).iter().map(|messages| messages.len() as i64).collect();
+        let mut offsets = Vec::new();
+        for (partition, length) in lengths.into_iter().enumerate() {
+            let offset = match to {
+                OffsetReset::Earliest => 0,
+                OffsetReset::Latest => length,
+                OffsetReset::Offset(offset) => offset.max(0).min(length),
+                OffsetReset::Time(_) => return Err(String::from("The memory broker doesn't keep the time of messages"))
+            };
+            state.committed.insert((group.to_string(), topic.to_string(), partition as i32), offset);
+            offsets.push((partition as i32, offset));
+        }
+        Ok(offsets)
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.0.lock().expect("Memory broker lock poisoned")
+    }
+}
+
+fn to_messages(messages: &[(Vec<u8>, Vec<u8>)], first_offset: i64) -> Vec<Message> {
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, (key, value))| Message {
+            offset: first_offset + i as i64,
+            key: key.clone(),
+            value: value.clone()
+        })
+        .collect()
+}
+
+pub struct MemoryConsumer {
+    broker: MemoryBroker,
+    group: String,
+    topics: Vec<String>,
+    /// Offset of the next message to poll, per topic and partition.
+    positions: HashMap<(String, i32), i64>,
+    /// Offset of the last message marked as processed, per topic and partition.
+    consumed: HashMap<(String, i32), i64>,
+    poll_wait: Duration
+}
+
+impl MemoryConsumer {
+    /// How long a poll waits for new messages when there are none, 100ms by default.
+    pub fn with_poll_wait(mut self, poll_wait: Duration) -> MemoryConsumer {
+        self.poll_wait = poll_wait;
+        self
+    }
+
+    fn fetch(&mut self, state: &mut State) -> Vec<MessageSet> {
+        let mut sets = Vec::new();
+        for topic in self.topics.iter() {
+            state.partitions(topic, self.broker.partitions);
+            let State {
+                topics,
+                committed,
+                ..
+            } = &mut *state;
+            for (partition, messages) in topics[topic].iter().enumerate() {
+                let partition = partition as i32;
+                let committed = committed.get(&(self.group.clone(), topic.clone(), partition)).cloned();
+                let position = *self.positions.entry((topic.clone(), partition)).or_insert_with(|| committed.unwrap_or(0));
+                let start = (position as usize).min(messages.len());
+                let end = (start + MAX_POLL_MESSAGES).min(messages.len());
+                if start < end {
+                    sets.push(MessageSet {
+                        topic: topic.clone(),
+                        partition,
+                        messages: to_messages(&messages[start..end], start as i64)
+                    });
+                    self.positions.insert((topic.clone(), partition), end as i64);
+                }
+            }
+        }
+        sets
+    }
+}
+
+impl ConsumerBackend for MemoryConsumer {
+    /// Waits a short while for new messages when there are none.
+    fn poll(&mut self) -> Result<Vec<MessageSet>, String> {
+        let broker = self.broker.clone();
+        let mut state = broker.lock();
+        let sets = self.fetch(&mut state);
+        if !sets.is_empty() {
+            return Ok(sets);
+        }
+        let (mut state, _timeout) = broker.state.1.wait_timeout(state, self.poll_wait).map_err(|e| e.to_string())?;
+        Ok(self.fetch(&mut state))
+    }
+
+    fn consume_message(&mut self, topic: &str, partition: i32, offset: i64) -> Result<(), String> {
+        self.consumed.insert((topic.to_string(), partition), offset);
+        Ok(())
+    }
+
+    fn commit_consumed(&mut self) -> Result<(), String> {
+        let mut state = self.broker.lock();
+        for ((topic, partition), offset) in self.consumed.iter() {
+            state.committed.insert((self.group.clone(), topic.clone(), *partition), offset + 1);
+        }
+        Ok(())
+    }
+}
+
+pub struct MemoryProducer {
+    broker: MemoryBroker
+}
+
+impl ProducerBackend for MemoryProducer {
+    fn partitions(&mut self, _topic: &str) -> Result<i32, String> {
+        Ok(self.broker.partitions)
+    }
+
+    fn send_batch(&mut self, topic: &str, partition: i32, records: &[(&[u8], &[u8])]) -> Result<i64, String> {
+        let mut state = self.broker.lock();
+        let messages = match state.partitions(topic, self.broker.partitions).get_mut(partition as usize) {
+            Some(v) => v,
+            None => return Err(format!("Partition {} of {} doesn't exist", partition, topic))
+        };
+        let first_offset = messages.len() as i64;
+        messages.extend(records.iter().map(|(key, value)| (key.to_vec(), value.to_vec())));
+        self.broker.state.1.notify_all();
+        Ok(first_offset)
+    }
+}
+
+/// Helpers for the tests that run against the global broker, with the schema registry of `serve_for_tests`. The tests
+/// of a process share the broker, so they look for their own records instead of counting all of them.
+#[cfg(test)]
+pub mod testing {
+    use super::MemoryBroker;
+    use crate::backend::{Delivery, Message, ProducerBackend};
+    use crate::dead_letter::{dlq_topic, DeadLetter};
+    use crate::embedded_registry::serve_for_tests;
+    use crate::events::AvroRecord;
+    use crate::kafka_producer::{AvroEncoder, Key, SubjectNameKind};
+    use avro_rs::types::Value;
+    use schema_registry_converter::Decoder;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Encodes the record and sends it to the global broker.
+    pub fn produce<R: AvroRecord>(key: Key, record: &R) -> Delivery {
+        serve_for_tests();
+        let strategy = SubjectNameKind::for_topic(R::TOPIC).map(|kind| kind.strategy(R::TOPIC, R::NAME, false));
+        let encoded = strategy.and_then(|strategy| AvroEncoder::from_env().encode(R::TOPIC, key, record.to_values(), &strategy));
+        match encoded.and_then(|(key, value)| MemoryBroker::global().producer().send(R::TOPIC, &key, &value)) {
+            Ok(delivery) => delivery,
+            Err(e) => panic!("Error producing to {}: {}", R::TOPIC, e)
+        }
+    }
+
+    /// Messages of all partitions of the topic.
+    pub fn messages(topic: &str) -> Vec<Message> {
+        let broker = MemoryBroker::global();
+        (0..broker.partitions).flat_map(|partition| broker.messages(topic, partition)).collect()
+    }
+
+    /// Records of the topic of `R` that decode, skipping anything else tests put on it.
+    pub fn records<R: AvroRecord>() -> Vec<R> {
+        let mut decoder = Decoder::new(serve_for_tests().to_string());
+        messages(R::TOPIC)
+            .iter()
+            .filter_map(|message| match decoder.decode(Some(&message.value)) {
+                Ok(Value::Record(values)) => R::from_values(&values).ok(),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// Dead letters of messages from `topic`.
+    pub fn dead_letters(topic: &str) -> Vec<DeadLetter> {
+        messages(&dlq_topic(topic))
+            .iter()
+            .map(|message| serde_json::from_slice(&message.value).expect("Dead letter should be json"))
+            .collect()
+    }
+
+    /// Waits until `check` returns something, failing the test when that takes over 10 seconds.
+    pub fn eventually<T, F: FnMut() -> Option<T>>(what: &str, mut check: F) -> T {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(v) = check() {
+                return v;
+            }
+            assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use crate::backend::{partition_for, Backend, Delivery, ProducerBackend};
+use crate::config::KafkaConfig;
+use crate::events::AvroRecord;
+use crate::kafka_consumer::{MessageContext, ProcessError};
+use crate::kafka_producer::{AvroEncoder, InFlight, Key, SubjectNameKind};
+use crate::shutdown::Shutdown;
+use crate::supervisor::panic_message;
+use avro_rs::types::Value;
+use log::{error, info};
+use schema_registry_converter::schema_registry::SubjectNameStrategy;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long the threads wait for records before checking whether to stop.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+pub struct ProducerData {
+    topic: &'static str,
+    record_name: &'static str,
+    key: Key,
+    values: Vec<(&'static str, Value)>
+}
+
+impl ProducerData {
+    pub fn new<R: AvroRecord>(key: String, record: &R) -> Self {
+        ProducerData {
+            topic: R::TOPIC,
+            record_name: R::NAME,
+            key: Key::Raw(key),
+            values: record.to_values()
+        }
+    }
+
+    pub fn with_key<K: AvroRecord, R: AvroRecord>(key: &K, record: &R) -> Self {
+        ProducerData {
+            topic: R::TOPIC,
+            record_name: R::NAME,
+            key: Key::Avro {
+                record_name: K::NAME,
+                values: key.to_values()
+            },
+            values: record.to_values()
+        }
+    }
+}
+
+/// Why a record could not be queued.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueError {
+    /// All places in the queue are taken, trying again later might work.
+    Full,
+    /// The producer stopped, because the service is shutting down.
+    Stopped
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "producer queue is full"),
+            QueueError::Stopped => write!(f, "producer stopped")
+        }
+    }
+}
+
+/// Outcome of sending a queued record, completed by the producer once the broker answered.
+pub struct Receipt {
+    outcome: Receiver<Result<Delivery, String>>,
+    timeout: Duration
+}
+
+impl Receipt {
+    /// Waits at most `wait` for the record to be acknowledged, none when the broker didn't answer by then. The record
+    /// is still send when the caller stops waiting, the producer stats and logs have its outcome. Fails when it isn't
+    /// acknowledged within the delivery timeout.
+    pub fn wait_at_most(self, wait: Duration) -> Result<Option<Delivery>, String> {
+        match self.outcome.recv_timeout(wait.min(self.timeout)) {
+            Ok(outcome) => outcome.map(Some),
+            Err(RecvTimeoutError::Timeout) if wait < self.timeout => Ok(None),
+            Err(RecvTimeoutError::Timeout) => Err(format!("Record not acknowledged within {:?}", self.timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(String::from("Producer stopped before sending the record"))
+        }
+    }
+}
+
+/// Counters of the producer since it started, as shown by `/v1/producer`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProducerStats {
+    /// Records acknowledged by the broker.
+    pub sent: u64,
+    /// Records that could not be encoded or were not acknowledged.
+    pub failed: u64,
+    /// Requests the sent and failed records were send in.
+    pub batches: u64,
+    /// Encoded size of the sent records.
+    pub bytes: u64,
+    pub records_per_second: f64,
+    /// Time from queueing a record until it was acknowledged, over the sent records.
+    pub average_latency_ms: f64,
+    pub max_latency_ms: f64
+}
+
+struct Counters {
+    started: Instant,
+    sent: u64,
+    failed: u64,
+    batches: u64,
+    bytes: u64,
+    total_latency: Duration,
+    max_latency: Duration
+}
+
+impl Counters {
+    fn new() -> Counters {
+        Counters {
+            started: Instant::now(),
+            sent: 0,
+            failed: 0,
+            batches: 0,
+            bytes: 0,
+            total_latency: Duration::from_secs(0),
+            max_latency: Duration::from_secs(0)
+        }
+    }
+
+    fn stats(&self) -> ProducerStats {
+        let average_latency = if self.sent == 0 {
+            0.0
+        } else {
+            self.total_latency.as_secs_f64() / self.sent as f64
+        };
+        ProducerStats {
+            sent: self.sent,
+            failed: self.failed,
+            batches: self.batches,
+            bytes: self.bytes,
+            records_per_second: self.sent as f64 / self.started.elapsed().as_secs_f64(),
+            average_latency_ms: average_latency * 1000.0,
+            max_latency_ms: self.max_latency.as_secs_f64() * 1000.0
+        }
+    }
+}
+
+/// Where the outcome of a queued record goes.
+struct Outcome {
+    sender: Sender<Result<Delivery, String>>,
+    /// Records emitted for the consumed message the record was queued for, if any.
+    emitted: Option<InFlight>
+}
+
+impl Outcome {
+    fn complete(self, outcome: Result<Delivery, String>) {
+        match (&self.emitted, &outcome) {
+            (Some(emitted), Ok(_)) => emitted.acknowledged(),
+            (Some(emitted), Err(e)) => emitted.failed(e.clone()),
+            (None, _) => ()
+        }
+        // The one waiting for the receipt might have given up already.
+        let _ = self.sender.send(outcome);
+    }
+}
+
+struct Queued {
+    producer_data: ProducerData,
+    queued_at: Instant,
+    outcome: Outcome
+}
+
+/// Queues records for the producer.
+#[derive(Clone)]
+pub struct ProducerSender {
+    sender: SyncSender<Queued>,
+    delivery_timeout: Duration,
+    counters: Arc<Mutex<Counters>>
+}
+
+impl ProducerSender {
+    /// Queues a record the handler of the message of `ctx` emitted, waiting while the queue is full, as consumer
+    /// handlers can slow down consuming. When `commit_after_acks` is set, the offset of the message isn't committed
+    /// before the record is acknowledged, or the message is moved to the dead letter topic because the record failed.
+    pub fn send(&self, ctx: &MessageContext, producer_data: ProducerData) -> Result<Receipt, ProcessError> {
+        ctx.emitted.add();
+        let (queued, receipt) = self.queued(producer_data, Some(ctx.emitted.clone()));
+        match self.sender.send(queued) {
+            Ok(()) => Ok(receipt),
+            Err(e) => {
+                ctx.emitted.forget();
+                Err(ProcessError::Transient(e.to_string()))
+            }
+        }
+    }
+
+    /// Queues the record without waiting, for HTTP handlers that should rather refuse a request.
+    pub fn try_send(&self, producer_data: ProducerData) -> Result<Receipt, QueueError> {
+        let (queued, receipt) = self.queued(producer_data, None);
+        match self.sender.try_send(queued) {
+            Ok(()) => Ok(receipt),
+            Err(TrySendError::Full(_)) => Err(QueueError::Full),
+            Err(TrySendError::Disconnected(_)) => Err(QueueError::Stopped)
+        }
+    }
+
+    pub fn stats(&self) -> ProducerStats {
+        self.counters.lock().expect("Producer counters lock poisoned").stats()
+    }
+
+    fn queued(&self, producer_data: ProducerData, emitted: Option<InFlight>) -> (Queued, Receipt) {
+        let (sender, receiver) = mpsc::channel();
+        let receipt = Receipt {
+            outcome: receiver,
+            timeout: self.delivery_timeout
+        };
+        let queued = Queued {
+            producer_data,
+            queued_at: Instant::now(),
+            outcome: Outcome {
+                sender,
+                emitted
+            }
+        };
+        (queued, receipt)
+    }
+}
+
+/// Record as it leaves an encoder worker, numbered in queue order so the sender can restore that order.
+struct Encoded {
+    sequence: u64,
+    topic: &'static str,
+    payload: Result<(Vec<u8>, Vec<u8>), String>,
+    queued_at: Instant,
+    outcome: Outcome
+}
+
+/// Starts the producer with a queue of the configured size. Encoder workers take records from the queue, and a
+/// sender collects the encoded records in a batch per partition. A batch is send once it's full or its first record
+/// waited for the linger interval. Records with the same key end up on the same partition, and are send in the order
+/// they were queued, whichever worker encoded them. Everything queued is send, until `stop` is triggered and the
+/// queue has been drained, the returned handle is the one of the sender.
+pub fn start(config: &KafkaConfig, stop: Shutdown) -> (ProducerSender, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(config.producer.queue_size);
+    let counters = Arc::new(Mutex::new(Counters::new()));
+    let producer_sender = ProducerSender {
+        sender,
+        delivery_timeout: config.producer.delivery_timeout(),
+        counters: counters.clone()
+    };
+    let queue = Arc::new(Mutex::new((receiver, 0)));
+    let (encoded_sender, encoded_receiver) = mpsc::sync_channel(config.producer.queue_size);
+    for _ in 0..config.producer.encoder_workers.max(1) {
+        let queue = queue.clone();
+        let encoded_sender = encoded_sender.clone();
+        let stop = stop.clone();
+        thread::spawn(move || encode_loop(&queue, &encoded_sender, &stop));
+    }
+    let config = config.clone();
+    let handle = thread::spawn(move || send_loop(&encoded_receiver, &config, &counters));
+    (producer_sender, handle)
+}
+
+/// Encodes queued records until `stop` is triggered and the queue is empty.
+fn encode_loop(queue: &Mutex<(Receiver<Queued>, u64)>, sender: &SyncSender<Encoded>, stop: &Shutdown) {
+    let mut encoder = AvroEncoder::from_env();
+    let mut strategies = HashMap::new();
+    loop {
+        // Taking a record and its sequence number under the same lock keeps the numbers in queue order.
+        let (queued, sequence) = {
+            let mut queue = queue.lock().expect("Producer queue lock poisoned");
+            let (receiver, next_sequence) = &mut *queue;
+            match receiver.recv_timeout(IDLE_WAIT) {
+                Ok(v) => {
+                    *next_sequence += 1;
+                    (v, *next_sequence - 1)
+                }
+                Err(RecvTimeoutError::Timeout) if stop.is_triggered() => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        };
+        let Queued {
+            producer_data,
+            queued_at,
+            outcome
+        } = queued;
+        let topic = producer_data.topic;
+        // Every sequence number has to be handed over, or the sender keeps waiting for it, so a panic is a failure too.
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| encode(&mut encoder, &mut strategies, producer_data))) {
+            Ok(payload) => payload,
+            Err(panic) => {
+                encoder = AvroEncoder::from_env();
+                Err(format!("Encoding panicked: {}", panic_message(&*panic)))
+            }
+        };
+        let encoded = Encoded {
+            sequence,
+            topic,
+            payload,
+            queued_at,
+            outcome
+        };
+        if sender.send(encoded).is_err() {
+            error!("Producer sender stopped, encoder can't hand over records");
+            break;
+        }
+    }
+}
+
+fn encode(
+    encoder: &mut AvroEncoder,
+    strategies: &mut HashMap<(&'static str, &'static str), SubjectNameStrategy>,
+    producer_data: ProducerData
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let topic = producer_data.topic;
+    let record_name = producer_data.record_name;
+    let strategy = match strategies.entry((topic, record_name)) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(SubjectNameKind::for_topic(topic)?.strategy(topic, record_name, false))
+    };
+    encoder.encode(topic, producer_data.key, producer_data.values, strategy)
+}
+
+/// Encoded records waiting to be send to one partition.
+struct Batch {
+    started: Instant,
+    records: Vec<Pending>
+}
+
+struct Pending {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    queued_at: Instant,
+    outcome: Outcome
+}
+
+/// Collects the encoded records in batches and sends them, until all encoder workers stopped.
+fn send_loop(receiver: &Receiver<Encoded>, config: &KafkaConfig, counters: &Mutex<Counters>) {
+    let mut producer = match Backend::new(config).producer() {
+        Ok(p) => p,
+        Err(e) => panic!("Error creating producer: {}", e)
+    };
+    let batch_size = config.producer.batch_size.max(1);
+    let linger = config.producer.linger();
+    // Records that overtook an earlier one in another encoder worker, by sequence number.
+    let mut waiting = BTreeMap::new();
+    let mut next_sequence = 0;
+    let mut batches: HashMap<(&'static str, i32), Batch> = HashMap::new();
+    loop {
+        let wait = batches
+            .values()
+            .map(|batch| linger.checked_sub(batch.started.elapsed()).unwrap_or_default())
+            .min();
+        let stopped = match receiver.recv_timeout(wait.unwrap_or(IDLE_WAIT)) {
+            Ok(encoded) => {
+                waiting.insert(encoded.sequence, encoded);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true
+        };
+        while let Some(encoded) = waiting.remove(&next_sequence) {
+            next_sequence += 1;
+            let Encoded {
+                topic,
+                payload,
+                queued_at,
+                outcome,
+                ..
+            } = encoded;
+            let full = match payload.and_then(|(key, value)| Ok((producer.partitions(topic)?, key, value))) {
+                Ok((partitions, key, value)) => {
+                    let partition = partition_for(producer.partitioner(), &key, partitions);
+                    let batch = batches.entry((topic, partition)).or_insert_with(|| Batch {
+                        started: Instant::now(),
+                        records: Vec::new()
+                    });
+                    batch.records.push(Pending {
+                        key,
+                        value,
+                        queued_at,
+                        outcome
+                    });
+                    if batch.records.len() >= batch_size {
+                        Some(partition)
+                    } else {
+                        None
+                    }
+                }
+                Err(e) => {
+                    error!("Error producing record to {}: {}", topic, e);
+                    counters.lock().expect("Producer counters lock poisoned").failed += 1;
+                    outcome.complete(Err(e));
+                    None
+                }
+            };
+            if let Some(partition) = full {
+                let batch = batches.remove(&(topic, partition)).expect("Full batch is collected");
+                send_batch(&mut *producer, topic, partition, batch, counters);
+            }
+        }
+        let due: Vec<(&'static str, i32)> = batches
+            .iter()
+            .filter(|(_, batch)| stopped || batch.started.elapsed() >= linger)
+            .map(|(destination, _)| *destination)
+            .collect();
+        for (topic, partition) in due {
+            let batch = batches.remove(&(topic, partition)).expect("Due batch is collected");
+            send_batch(&mut *producer, topic, partition, batch, counters);
+        }
+        if stopped {
+            break;
+        }
+    }
+    info!("Producer stopped, all queued records are send");
+}
+
+fn send_batch(producer: &mut dyn ProducerBackend, topic: &str, partition: i32, batch: Batch, counters: &Mutex<Counters>) {
+    let records: Vec<(&[u8], &[u8])> = batch.records.iter().map(|pending| (&pending.key[..], &pending.value[..])).collect();
+    let bytes: usize = records.iter().map(|(key, value)| key.len() + value.len()).sum();
+    let result = producer.send_batch(topic, partition, &records);
+    let mut counters = counters.lock().expect("Producer counters lock poisoned");
+    counters.batches += 1;
+    match &result {
+        Ok(_) => {
+            counters.sent += records.len() as u64;
+            counters.bytes += bytes as u64;
+        }
+        Err(e) => {
+            error!("Error sending {} records to {}:{}: {}", records.len(), topic, partition, e);
+            counters.failed += records.len() as u64;
+        }
+    }
+    for (i, pending) in batch.records.into_iter().enumerate() {
+        let outcome = match &result {
+            Ok(first_offset) => {
+                let latency = pending.queued_at.elapsed();
+                counters.total_latency += latency;
+                counters.max_latency = counters.max_latency.max(latency);
+                Ok(Delivery {
+                    partition,
+                    offset: if *first_offset < 0 { -1 } else { first_offset + i as i64 }
+                })
+            }
+            Err(e) => Err(format!("Error sending message: {}", e))
+        };
+        pending.outcome.complete(outcome);
+    }
+}
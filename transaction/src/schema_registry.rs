@@ -0,0 +1,227 @@
+use crate::events::SchemaFile;
+use crate::kafka_producer::SubjectNameKind;
+use curl::easy::{Easy, List};
+use log::{error, info, warn};
+use serde_json::{json, Value as Json};
+use std::env;
+
+/// Compatibility level of a subject, as known by the schema registry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compatibility {
+    None,
+    Backward,
+    BackwardTransitive,
+    Forward,
+    ForwardTransitive,
+    Full,
+    FullTransitive
+}
+
+impl Compatibility {
+    /// Level of a name like `BACKWARD_TRANSITIVE`, in any case, none for an unknown name.
+    pub fn from_name(value: &str) -> Option<Compatibility> {
+        match value.to_uppercase().as_str() {
+            "NONE" => Some(Compatibility::None),
+            "BACKWARD" => Some(Compatibility::Backward),
+            "BACKWARD_TRANSITIVE" => Some(Compatibility::BackwardTransitive),
+            "FORWARD" => Some(Compatibility::Forward),
+            "FORWARD_TRANSITIVE" => Some(Compatibility::ForwardTransitive),
+            "FULL" => Some(Compatibility::Full),
+            "FULL_TRANSITIVE" => Some(Compatibility::FullTransitive),
+            _ => None
+        }
+    }
+
+    fn parse(value: &str) -> Compatibility {
+        match Compatibility::from_name(value) {
+            Some(compatibility) => compatibility,
+            None => panic!("Unknown schema compatibility {}", value)
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compatibility::None => "NONE",
+            Compatibility::Backward => "BACKWARD",
+            Compatibility::BackwardTransitive => "BACKWARD_TRANSITIVE",
+            Compatibility::Forward => "FORWARD",
+            Compatibility::ForwardTransitive => "FORWARD_TRANSITIVE",
+            Compatibility::Full => "FULL",
+            Compatibility::FullTransitive => "FULL_TRANSITIVE"
+        }
+    }
+}
+
+/// Whether an incompatible schema stops the service from starting, or is only logged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckMode {
+    Enforce,
+    Report
+}
+
+/// Compatibility for `subject`, from `SCHEMA_COMPATIBILITY_<SUBJECT>` with `-` and `.` replaced by `_`, like
+/// `SCHEMA_COMPATIBILITY_BALANCE_CHANGED_VALUE`, falling back to `SCHEMA_COMPATIBILITY`. None when neither is set, so
+/// the level the registry already has for the subject, or its global default, applies.
+fn compatibility(subject: &str) -> Option<Compatibility> {
+    let subject_var = format!("SCHEMA_COMPATIBILITY_{}", subject.to_uppercase().replace(|c: char| c == '-' || c == '.', "_"));
+    match env::var(subject_var).or_else(|_e| env::var("SCHEMA_COMPATIBILITY")) {
+        Ok(val) => Some(Compatibility::parse(&val)),
+        Err(_e) => None
+    }
+}
+
+fn check_mode() -> CheckMode {
+    match env::var("SCHEMA_CHECK_MODE") {
+        Ok(ref val) if val.eq_ignore_ascii_case("report") => CheckMode::Report,
+        _ => CheckMode::Enforce
+    }
+}
+
+/// Sets the compatibility for the subject of each key or value record when one is configured, checks the schema against
+/// the latest registered version and registers it. An incompatible schema is an error, unless `SCHEMA_CHECK_MODE` is
+/// `report`. Report mode leaves the compatibility as it is, so the schemas are checked against the level the registry
+/// already has.
+pub fn register_schemas(schemas: &[SchemaFile]) -> Result<(), String> {
+    let schema_registry_url = schema_registry_url();
+    let base_url = schema_registry_url.trim_end_matches('/');
+    let mode = check_mode();
+    for owned in schemas {
+        let subject = SubjectNameKind::for_topic(owned.topic)?.subject(owned.topic, owned.name, owned.is_key);
+        let schema = owned.schema;
+        let compatibility = compatibility(&subject);
+        match compatibility {
+            Some(compatibility) if mode == CheckMode::Enforce => set_compatibility(base_url, &subject, compatibility)?,
+            Some(compatibility) => info!("Not setting compatibility {} for {} in report mode", compatibility.as_str(), subject),
+            None => ()
+        }
+        let level = match compatibility {
+            Some(compatibility) => format!("{} compatible", compatibility.as_str()),
+            None => String::from("compatible")
+        };
+        if is_compatible(base_url, &subject, schema)? {
+            let id = register(base_url, &subject, schema)?;
+            info!("Registered schema for {} with id {}", subject, id);
+        } else if mode == CheckMode::Report {
+            error!("Schema for {} is not {} with the registered one, not registering it", subject, level);
+        } else {
+            return Err(format!(
+                "Schema for {} is not {} with the registered one, see migrate-schemas for a deliberate change",
+                subject, level
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Registers the schemas that aren't compatible with the latest registered version of their subject, for a change that
+/// can't be made compatible, like the decimal amounts that replaced doubles. The compatibility of such a subject is set
+/// to `NONE` for the registration only, and restored afterwards. Consumers with the old schema can't read records with
+/// the new one, so run it while the services that use the subjects are stopped, before starting the new versions.
+pub fn migrate_schemas(schemas: &[SchemaFile]) -> Result<(), String> {
+    let schema_registry_url = schema_registry_url();
+    let base_url = schema_registry_url.trim_end_matches('/');
+    for owned in schemas {
+        let subject = SubjectNameKind::for_topic(owned.topic)?.subject(owned.topic, owned.name, owned.is_key);
+        let schema = owned.schema;
+        if is_compatible(base_url, &subject, schema)? {
+            info!("Schema for {} is compatible, nothing to migrate", subject);
+            continue;
+        }
+        let previous = subject_compatibility(base_url, &subject)?;
+        set_compatibility(base_url, &subject, Compatibility::None)?;
+        let registered = register(base_url, &subject, schema);
+        let restored = match previous {
+            Some(compatibility) => set_compatibility(base_url, &subject, compatibility),
+            None => delete_compatibility(base_url, &subject)
+        };
+        let id = registered?;
+        restored.map_err(|e| format!("Registered schema for {} with id {}, but not restoring its compatibility: {}", subject, id, e))?;
+        info!("Migrated schema for {} to id {}", subject, id);
+    }
+    Ok(())
+}
+
+fn schema_registry_url() -> String {
+    match env::var("SCHEMA_REGISTRY_URL") {
+        Ok(val) => val,
+        Err(_e) => "127.0.0.1:8081".to_string()
+    }
+}
+
+/// Compatibility set for the subject itself, none when it uses the global default.
+fn subject_compatibility(base_url: &str, subject: &str) -> Result<Option<Compatibility>, String> {
+    match request("GET", &format!("{}/config/{}", base_url, subject), None)? {
+        (200, response) => match response["compatibilityLevel"].as_str() {
+            Some(compatibility) => Ok(Some(Compatibility::parse(compatibility))),
+            None => Err(format!("No compatibility in response for {}: {}", subject, response))
+        },
+        (404, _) => Ok(None),
+        (code, response) => Err(format!("Error getting compatibility for {}, status {}: {}", subject, code, response))
+    }
+}
+
+/// Removes the compatibility of the subject, so it uses the global default again.
+fn delete_compatibility(base_url: &str, subject: &str) -> Result<(), String> {
+    match request("DELETE", &format!("{}/config/{}", base_url, subject), None)? {
+        (200, _) => Ok(()),
+        (code, response) => Err(format!("Error deleting compatibility for {}, status {}: {}", subject, code, response))
+    }
+}
+
+fn set_compatibility(base_url: &str, subject: &str, compatibility: Compatibility) -> Result<(), String> {
+    let body = json!({ "compatibility": compatibility.as_str() });
+    match request("PUT", &format!("{}/config/{}", base_url, subject), Some(&body))? {
+        (200, _) => Ok(()),
+        (code, response) => Err(format!("Error setting compatibility for {}, status {}: {}", subject, code, response))
+    }
+}
+
+fn is_compatible(base_url: &str, subject: &str, schema: &str) -> Result<bool, String> {
+    let body = json!({ "schema": schema });
+    match request("POST", &format!("{}/compatibility/subjects/{}/versions/latest", base_url, subject), Some(&body))? {
+        (200, response) => Ok(response["is_compatible"].as_bool().unwrap_or(false)),
+        (404, _) => {
+            warn!("No schema registered yet for {}", subject);
+            Ok(true)
+        }
+        (code, response) => Err(format!("Error checking compatibility for {}, status {}: {}", subject, code, response))
+    }
+}
+
+fn register(base_url: &str, subject: &str, schema: &str) -> Result<i64, String> {
+    let body = json!({ "schema": schema });
+    match request("POST", &format!("{}/subjects/{}/versions", base_url, subject), Some(&body))? {
+        (200, response) => response["id"]
+            .as_i64()
+            .ok_or_else(|| format!("No id in response registering {}: {}", subject, response)),
+        (code, response) => Err(format!("Error registering schema for {}, status {}: {}", subject, code, response))
+    }
+}
+
+fn request(method: &str, url: &str, body: Option<&Json>) -> Result<(u32, Json), String> {
+    let mut easy = Easy::new();
+    let mut headers = List::new();
+    headers
+        .append("Content-Type: application/vnd.schemaregistry.v1+json")
+        .map_err(|e| e.to_string())?;
+    easy.http_headers(headers).map_err(|e| e.to_string())?;
+    easy.url(url).map_err(|e| e.to_string())?;
+    if let Some(body) = body {
+        easy.post_fields_copy(body.to_string().as_bytes()).map_err(|e| e.to_string())?;
+    }
+    easy.custom_request(method).map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|data| {
+                response.extend_from_slice(data);
+                Ok(data.len())
+            })
+            .map_err(|e| e.to_string())?;
+        transfer.perform().map_err(|e| format!("Error calling schema registry at {}: {}", url, e))?;
+    }
+    let code = easy.response_code().map_err(|e| e.to_string())?;
+    let json = serde_json::from_slice(&response).unwrap_or(Json::Null);
+    Ok((code, json))
+}
@@ -0,0 +1,239 @@
+use crate::kafka_consumer::{INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::shutdown::Shutdown;
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumerState {
+    /// Connecting to the broker for the first time.
+    Starting,
+    Running,
+    /// Connecting to the broker again after an error.
+    Reconnecting,
+    /// Waiting to start the consumer again after its thread crashed.
+    Restarting,
+    Stopped
+}
+
+/// State of a consumer, as shown by the status endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsumerStatus {
+    pub group: String,
+    pub topics: Vec<String>,
+    pub state: ConsumerState,
+    /// Moment the consumer got into its current state.
+    pub since: NaiveDateTime,
+    /// Topics that are not consumed until they're resumed.
+    pub paused: BTreeSet<String>,
+    pub reconnects: u32,
+    pub restarts: u32,
+    pub last_error: Option<String>
+}
+
+impl ConsumerStatus {
+    fn consumes(&self, topic: &str) -> Result<(), TopicError> {
+        if self.topics.is_empty() {
+            Err(TopicError::NotStarted)
+        } else if self.topics.iter().any(|t| t == topic) {
+            Ok(())
+        } else {
+            Err(TopicError::NotConsumed)
+        }
+    }
+}
+
+/// Why a topic can't be paused or resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopicError {
+    /// The consumer didn't register its topics yet, which it does right after the service started.
+    NotStarted,
+    NotConsumed
+}
+
+impl TopicError {
+    /// A 503 while the consumer is starting, as trying again shortly will work, and a 404 for a topic that isn't
+    /// consumed.
+    fn response(self, topic: &str) -> Custom<String> {
+        match self {
+            TopicError::NotStarted => Custom(Status::ServiceUnavailable, String::from("Consumer is still starting, try again in a moment")),
+            TopicError::NotConsumed => Custom(Status::NotFound, format!("{} is not consumed", topic))
+        }
+    }
+}
+
+/// Shared status of a consumer, updated by the consumer and its supervisor.
+#[derive(Clone)]
+pub struct ConsumerMonitor(Arc<Mutex<ConsumerStatus>>);
+
+impl ConsumerMonitor {
+    pub fn new(group: &str) -> ConsumerMonitor {
+        ConsumerMonitor(Arc::new(Mutex::new(ConsumerStatus {
+            group: group.to_string(),
+            topics: Vec::new(),
+            state: ConsumerState::Starting,
+            since: Utc::now().naive_utc(),
+            paused: BTreeSet::new(),
+            reconnects: 0,
+            restarts: 0,
+            last_error: None
+        })))
+    }
+
+    pub fn status(&self) -> ConsumerStatus {
+        self.0.lock().expect("Consumer monitor lock poisoned").clone()
+    }
+
+    pub fn starting(&self, topics: &[String]) {
+        self.update(ConsumerState::Starting, None, |status| status.topics = topics.to_vec())
+    }
+
+    pub fn running(&self) {
+        self.update(ConsumerState::Running, None, |_| ())
+    }
+
+    pub fn reconnecting(&self, error: String) {
+        self.update(ConsumerState::Reconnecting, Some(error), |status| status.reconnects += 1)
+    }
+
+    pub fn restarting(&self, error: String) {
+        self.update(ConsumerState::Restarting, Some(error), |status| status.restarts += 1)
+    }
+
+    pub fn stopped(&self) {
+        self.update(ConsumerState::Stopped, None, |_| ())
+    }
+
+    /// Stops consuming `topic` until it's resumed.
+    pub fn pause(&self, topic: &str) -> Result<(), TopicError> {
+        let mut status = self.0.lock().expect("Consumer monitor lock poisoned");
+        status.consumes(topic)?;
+        status.paused.insert(topic.to_string());
+        Ok(())
+    }
+
+    /// Continues consuming `topic`.
+    pub fn resume(&self, topic: &str) -> Result<(), TopicError> {
+        let mut status = self.0.lock().expect("Consumer monitor lock poisoned");
+        status.consumes(topic)?;
+        status.paused.remove(topic);
+        Ok(())
+    }
+
+    /// The topics that aren't paused, in the same order.
+    pub fn unpaused(&self, topics: &[String]) -> Vec<String> {
+        let status = self.0.lock().expect("Consumer monitor lock poisoned");
+        topics.iter().filter(|topic| !status.paused.contains(*topic)).cloned().collect()
+    }
+
+    /// Sets the state, keeping the last error when there is no new one.
+    fn update<F: FnOnce(&mut ConsumerStatus)>(&self, state: ConsumerState, error: Option<String>, f: F) {
+        let mut status = self.0.lock().expect("Consumer monitor lock poisoned");
+        if status.state != state {
+            status.state = state;
+            status.since = Utc::now().naive_utc();
+        }
+        if error.is_some() {
+            status.last_error = error;
+        }
+        f(&mut status)
+    }
+}
+
+/// State of the consumer of the service, also answered during shutdown.
+#[get("/status")]
+pub fn status(monitor: State<ConsumerMonitor>) -> Json<ConsumerStatus> {
+    Json(monitor.status())
+}
+
+#[post("/admin/topics/<topic>/pause")]
+pub fn pause(topic: String, monitor: State<ConsumerMonitor>) -> Result<Json<ConsumerStatus>, Custom<String>> {
+    match monitor.pause(&topic) {
+        Ok(()) => {
+            info!("Paused consuming {}", topic);
+            Ok(Json(monitor.status()))
+        }
+        Err(e) => Err(e.response(&topic))
+    }
+}
+
+#[post("/admin/topics/<topic>/resume")]
+pub fn resume(topic: String, monitor: State<ConsumerMonitor>) -> Result<Json<ConsumerStatus>, Custom<String>> {
+    match monitor.resume(&topic) {
+        Ok(()) => {
+            info!("Resumed consuming {}", topic);
+            Ok(Json(monitor.status()))
+        }
+        Err(e) => Err(e.response(&topic))
+    }
+}
+
+/// Runs the thread returned by `start` on its own thread, starting it again with backoff when it panics, until it
+/// ends normally or `shutdown` is triggered. The backoff is reset when the thread ran for longer than the maximum
+/// backoff.
+pub fn supervise<F>(monitor: ConsumerMonitor, shutdown: Shutdown, start: F) -> JoinHandle<()>
+where
+    F: Fn() -> JoinHandle<()> + Send + 'static
+{
+    thread::spawn(move || {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let panic = match start().join() {
+                Ok(()) => break,
+                Err(panic) => panic_message(&*panic)
+            };
+            if shutdown.is_triggered() {
+                error!("Consumer crashed during shutdown because of {}", panic);
+                break;
+            }
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = INITIAL_BACKOFF;
+            }
+            error!("Consumer crashed because of {}, restarting in {:?}", panic, backoff);
+            monitor.restarting(panic);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            info!("Restarting consumer");
+        }
+        monitor.stopped();
+    })
+}
+
+/// The message of a caught panic.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<String>(), panic.downcast_ref::<&str>()) {
+        (Some(message), _) => message.clone(),
+        (_, Some(message)) => message.to_string(),
+        _ => String::from("unknown panic")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConsumerMonitor, TopicError};
+
+    #[test]
+    fn topics_can_only_be_paused_once_the_consumer_registered_them() {
+        let monitor = ConsumerMonitor::new("group");
+        assert_eq!(monitor.pause("transfers"), Err(TopicError::NotStarted));
+
+        let topics = vec![String::from("accounts"), String::from("transfers")];
+        monitor.starting(&topics);
+        assert_eq!(monitor.pause("unknown"), Err(TopicError::NotConsumed));
+        assert_eq!(monitor.pause("transfers"), Ok(()));
+        assert_eq!(monitor.unpaused(&topics), vec![String::from("accounts")]);
+        assert_eq!(monitor.resume("transfers"), Ok(()));
+        assert_eq!(monitor.unpaused(&topics), topics);
+    }
+}